@@ -0,0 +1,425 @@
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use mysql::prelude::*;
+use mysql::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::DatabaseConfig;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+// Số row gộp trong 1 câu INSERT của file dump
+const ROWS_PER_INSERT: usize = 500;
+
+// ============ Manifest ============
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub created_at: String,
+    pub servers: Vec<ServerBackup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerBackup {
+    pub server: u8,
+    pub host: String,
+    pub port: u16,
+    pub database: String,
+    pub tables: Vec<TableBackup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableBackup {
+    pub table: String,
+    /// Đường dẫn file dump, tương đối so với thư mục backup
+    pub file: String,
+    pub row_count: u64,
+    pub sha256: String,
+}
+
+// ============ Backup ============
+
+/// Tạo thư mục backup mới dạng `<backup_directory>/<run_id>`
+pub fn create_backup_dir(base: &str, run_id: &str) -> Result<PathBuf> {
+    let dir = Path::new(base).join(run_id);
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Không thể tạo thư mục backup {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Dump schema + data của các bảng vào `<backup_dir>/server{n}/<table>.sql`.
+/// Toàn bộ các bảng được đọc trong cùng 1 snapshot nên dữ liệu nhất quán với nhau.
+pub fn backup_server(
+    pool: &Pool,
+    server: u8,
+    db_config: &DatabaseConfig,
+    tables: &[String],
+    backup_dir: &Path,
+) -> Result<ServerBackup> {
+    println!(
+        "  Backup Server {} ({}:{}/{})...",
+        server, db_config.host, db_config.port, db_config.database
+    );
+
+    let server_dir = format!("server{}", server);
+    fs::create_dir_all(backup_dir.join(&server_dir))?;
+
+    let mut conn = pool.get_conn()?;
+    conn.query_drop("START TRANSACTION WITH CONSISTENT SNAPSHOT, READ ONLY")?;
+
+    let mut table_backups = Vec::new();
+    for table in tables {
+        let exists: Option<String> = conn.exec_first(
+            "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+            (table,),
+        )?;
+        if exists.is_none() {
+            println!("  {} Bảng {} không tồn tại, bỏ qua", "-".dimmed(), table);
+            continue;
+        }
+
+        let file = format!("{}/{}.sql", server_dir, table);
+        let table_backup = dump_table(&mut conn, table, &backup_dir.join(&file), file)
+            .with_context(|| format!("Backup bảng {} của Server {} thất bại", table, server))?;
+        table_backups.push(table_backup);
+    }
+
+    conn.query_drop("COMMIT")?;
+
+    Ok(ServerBackup {
+        server,
+        host: db_config.host.clone(),
+        port: db_config.port,
+        database: db_config.database.clone(),
+        tables: table_backups,
+    })
+}
+
+fn dump_table(
+    conn: &mut PooledConn,
+    table: &str,
+    path: &Path,
+    relative_file: String,
+) -> Result<TableBackup> {
+    let (_, create_sql): (String, String) = conn
+        .query_first(format!("SHOW CREATE TABLE `{}`", table))?
+        .context("SHOW CREATE TABLE không trả về kết quả")?;
+    let total: Option<u64> = conn.query_first(format!("SELECT COUNT(*) FROM `{}`", table))?;
+
+    let pb = ProgressBar::new(total.unwrap_or(0));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
+            .unwrap(),
+    );
+    pb.set_message(table.to_string());
+
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    // Mỗi câu lệnh nằm trên 1 dòng để restore có thể đọc lại theo dòng
+    writeln!(
+        writer,
+        "-- Backup bảng `{}` lúc {}",
+        table,
+        Local::now().to_rfc3339()
+    )?;
+    writeln!(writer, "DROP TABLE IF EXISTS `{}`;", table)?;
+    writeln!(writer, "{};", create_sql.replace('\n', " "))?;
+
+    let mut row_count: u64 = 0;
+    let mut batch: Vec<String> = Vec::with_capacity(ROWS_PER_INSERT);
+    let result = conn.query_iter(format!("SELECT * FROM `{}`", table))?;
+    for row in result {
+        let row = row?;
+        let values: Vec<String> = row.unwrap().iter().map(sql_literal).collect();
+        batch.push(format!("({})", values.join(", ")));
+        row_count += 1;
+
+        if batch.len() >= ROWS_PER_INSERT {
+            write_insert(&mut writer, table, &batch)?;
+            batch.clear();
+        }
+        pb.inc(1);
+    }
+    if !batch.is_empty() {
+        write_insert(&mut writer, table, &batch)?;
+    }
+    writer.flush()?;
+    drop(writer);
+
+    pb.finish_with_message(format!("✓ {}", table));
+
+    Ok(TableBackup {
+        table: table.to_string(),
+        file: relative_file,
+        row_count,
+        sha256: file_sha256(path)?,
+    })
+}
+
+fn write_insert(writer: &mut impl Write, table: &str, rows: &[String]) -> Result<()> {
+    writeln!(
+        writer,
+        "INSERT INTO `{}` VALUES {};",
+        table,
+        rows.join(", ")
+    )?;
+    Ok(())
+}
+
+/// Chuyển giá trị sang literal SQL. Bytes chứa ký tự điều khiển (BIT, BLOB...)
+/// được ghi dạng hex để không bị thay đổi khi đọc lại.
+pub fn sql_literal(value: &Value) -> String {
+    if let Value::Bytes(bytes) = value {
+        let is_text = std::str::from_utf8(bytes)
+            .map(|s| {
+                !s.chars()
+                    .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+            })
+            .unwrap_or(false);
+        if !is_text {
+            if bytes.is_empty() {
+                return "''".to_string();
+            }
+            let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            return format!("0x{}", hex);
+        }
+    }
+    value.as_sql(false)
+}
+
+pub fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn write_manifest(backup_dir: &Path, manifest: &BackupManifest) -> Result<()> {
+    let path = backup_dir.join(MANIFEST_FILE);
+    fs::write(&path, serde_json::to_string_pretty(manifest)?)
+        .with_context(|| format!("Không thể ghi {}", path.display()))?;
+    Ok(())
+}
+
+// ============ Restore ============
+
+pub fn read_manifest(backup_dir: &Path) -> Result<BackupManifest> {
+    let path = backup_dir.join(MANIFEST_FILE);
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Không đọc được manifest {}", path.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("Manifest lỗi: {}", path.display()))
+}
+
+/// Kiểm tra checksum và số row của từng file dump so với manifest.
+/// Chỉ đọc file, chưa đụng vào database.
+pub fn verify_server_backup(backup_dir: &Path, server_backup: &ServerBackup) -> Result<()> {
+    let mut errors = Vec::new();
+
+    for table in &server_backup.tables {
+        let path = backup_dir.join(&table.file);
+        if !path.exists() {
+            errors.push(format!("{}: thiếu file {}", table.table, table.file));
+            continue;
+        }
+
+        let sha256 = file_sha256(&path)?;
+        if sha256 != table.sha256 {
+            errors.push(format!(
+                "{}: checksum không khớp (manifest {}, file {})",
+                table.table, table.sha256, sha256
+            ));
+            continue;
+        }
+
+        let mut row_count = 0;
+        for statement in read_statements(&path)? {
+            row_count += count_insert_rows(&statement);
+        }
+        if row_count != table.row_count {
+            errors.push(format!(
+                "{}: số row không khớp (manifest {}, file {})",
+                table.table, table.row_count, row_count
+            ));
+            continue;
+        }
+
+        println!(
+            "{} {:<25} {:>8} rows, checksum OK",
+            "✓".green(),
+            table.table,
+            table.row_count
+        );
+    }
+
+    if !errors.is_empty() {
+        for error in &errors {
+            println!("{} {}", "✗".red(), error);
+        }
+        bail!("Backup không hợp lệ ({} lỗi), không restore", errors.len());
+    }
+    Ok(())
+}
+
+/// Drop và tạo lại từng bảng từ file dump, sau đó so số row với manifest
+pub fn restore_server(
+    conn: &mut PooledConn,
+    backup_dir: &Path,
+    server_backup: &ServerBackup,
+) -> Result<()> {
+    conn.query_drop("SET FOREIGN_KEY_CHECKS=0")?;
+
+    for table in &server_backup.tables {
+        let pb = ProgressBar::new(table.row_count);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
+                .unwrap(),
+        );
+        pb.set_message(table.table.clone());
+
+        for statement in read_statements(&backup_dir.join(&table.file))? {
+            conn.query_drop(&statement)
+                .with_context(|| format!("Restore bảng {} thất bại", table.table))?;
+            pb.inc(count_insert_rows(&statement));
+        }
+
+        pb.finish_with_message(format!("✓ {}", table.table));
+
+        let restored: Option<u64> =
+            conn.query_first(format!("SELECT COUNT(*) FROM `{}`", table.table))?;
+        let restored = restored.unwrap_or(0);
+        if restored != table.row_count {
+            bail!(
+                "Bảng {} sau restore có {} rows, manifest ghi {}",
+                table.table,
+                restored,
+                table.row_count
+            );
+        }
+    }
+
+    conn.query_drop("SET FOREIGN_KEY_CHECKS=1")?;
+    Ok(())
+}
+
+/// Đọc các câu lệnh SQL trong file dump (mỗi dòng 1 câu, bỏ qua comment)
+fn read_statements(path: &Path) -> Result<Vec<String>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Không đọc được {}", path.display()))?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with("--"))
+        .map(|line| line.trim_end_matches(';').to_string())
+        .collect())
+}
+
+/// Đếm số tuple trong 1 câu `INSERT ... VALUES (...), (...)`, bỏ qua dấu ngoặc nằm trong chuỗi
+fn count_insert_rows(statement: &str) -> u64 {
+    if !statement.starts_with("INSERT INTO") {
+        return 0;
+    }
+    let values = match statement.find(" VALUES ") {
+        Some(pos) => &statement[pos..],
+        None => return 0,
+    };
+
+    let mut rows = 0;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in values.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '\'' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '\'' => in_string = true,
+            '(' => {
+                if depth == 0 {
+                    rows += 1;
+                }
+                depth += 1;
+            }
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_tuples() {
+        assert_eq!(count_insert_rows("INSERT INTO `player` VALUES (1,'a')"), 1);
+        assert_eq!(
+            count_insert_rows("INSERT INTO `player` VALUES (1,'a'),(2,'b'),(3,NULL)"),
+            3
+        );
+        assert_eq!(
+            count_insert_rows("INSERT INTO `player` (`id`, `name`) VALUES (1,'a'), (2,'b')"),
+            2
+        );
+    }
+
+    #[test]
+    fn ignores_separators_inside_strings() {
+        assert_eq!(
+            count_insert_rows("INSERT INTO `clan` VALUES (1,'a),(b'),(2,'(x)')"),
+            2
+        );
+        assert_eq!(
+            count_insert_rows("INSERT INTO `clan` VALUES (1,'{\"m\":[1,2]}'),(2,'),(')"),
+            2
+        );
+    }
+
+    #[test]
+    fn handles_escaped_quotes() {
+        assert_eq!(
+            count_insert_rows(r"INSERT INTO `player` VALUES (1,'it\'s (ok)'),(2,'b')"),
+            2
+        );
+        assert_eq!(
+            count_insert_rows(r"INSERT INTO `player` VALUES (1,'a\\'),(2,'b\\\'),(')"),
+            2
+        );
+        assert_eq!(
+            count_insert_rows("INSERT INTO `player` VALUES (1,'it''s),('),(2,'b')"),
+            2
+        );
+    }
+
+    #[test]
+    fn handles_multi_line_values() {
+        let statement = "INSERT INTO `mail` VALUES (1,'dòng 1\n),(dòng 2'),\n(2,'x\r\ny')";
+        assert_eq!(count_insert_rows(statement), 2);
+    }
+
+    #[test]
+    fn ignores_other_statements() {
+        assert_eq!(
+            count_insert_rows("DELETE FROM `player` WHERE id IN (1,2)"),
+            0
+        );
+        assert_eq!(
+            count_insert_rows("INSERT INTO `player` SELECT * FROM `tmp`"),
+            0
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use mysql::{Params, Value};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::report::InsertTiming;
+
+// MySQL giới hạn 65535 placeholder trong 1 prepared statement
+const MAX_PLACEHOLDERS: usize = 65535;
+
+// Số row đầu mỗi bảng được insert từng row 1, làm mẫu so sánh với insert nhiều row
+pub const PER_ROW_SAMPLE: u64 = 50;
+
+// ============ Multi-row INSERT ============
+
+/// Tổng thời gian chạy câu lệnh trên đích, cộng dồn ở nơi thực thi (thread ghi của
+/// `merge_parallel` hoặc thread hiện tại), không tính thời gian đọc nguồn / xếp hàng.
+#[derive(Debug, Clone, Default)]
+pub struct ExecTimer(Arc<Mutex<Duration>>);
+
+impl ExecTimer {
+    pub fn time<T>(&self, run: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = run();
+        *self.0.lock().unwrap() += started.elapsed();
+        result
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Gom nhiều row thành 1 câu `INSERT ... VALUES (...), (...)` tối đa `batch_size` row,
+/// đồng thời đếm số row / số câu lệnh để ghi vào report. Thời gian chạy được đo qua timer
+/// trả về kèm câu lệnh, các row mẫu (insert từng row) đo bằng timer riêng.
+pub struct InsertBatch {
+    table: String,
+    columns_str: String,
+    row_placeholder: String,
+    max_rows: usize,
+    params: Vec<Value>,
+    rows: usize,
+    total_rows: u64,
+    statements: u64,
+    sample_rows: u64,
+    sample_timer: ExecTimer,
+    timer: ExecTimer,
+}
+
+impl InsertBatch {
+    pub fn new(table: &str, columns: &[&str], batch_size: usize) -> Self {
+        let max_rows = batch_size.clamp(1, MAX_PLACEHOLDERS / columns.len().max(1));
+        Self {
+            table: table.to_string(),
+            columns_str: columns
+                .iter()
+                .map(|c| format!("`{}`", c))
+                .collect::<Vec<_>>()
+                .join(", "),
+            row_placeholder: format!("({})", vec!["?"; columns.len()].join(", ")),
+            max_rows,
+            params: Vec::new(),
+            rows: 0,
+            total_rows: 0,
+            statements: 0,
+            sample_rows: 0,
+            sample_timer: ExecTimer::default(),
+            timer: ExecTimer::default(),
+        }
+    }
+
+    /// `rows` row đầu tiên được insert từng row 1 để so sánh tốc độ trong report
+    pub fn with_per_row_sample(mut self, rows: u64) -> Self {
+        self.sample_rows = rows;
+        self
+    }
+
+    /// Thêm 1 row, trả về câu lệnh cần chạy khi đủ batch (hoặc ngay khi còn trong mẫu)
+    pub fn push(&mut self, row: Vec<Value>) -> Option<(String, Params, &ExecTimer)> {
+        self.params.extend(row);
+        self.rows += 1;
+        if self.rows >= self.max_rows || self.total_rows < self.sample_rows {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// Câu lệnh cho các row còn lại (nếu có)
+    pub fn flush(&mut self) -> Option<(String, Params, &ExecTimer)> {
+        if self.rows == 0 {
+            return None;
+        }
+        let sql = format!(
+            "INSERT INTO `{}` ({}) VALUES {}",
+            self.table,
+            self.columns_str,
+            vec![self.row_placeholder.as_str(); self.rows].join(", ")
+        );
+        let timer = if self.total_rows < self.sample_rows {
+            &self.sample_timer
+        } else {
+            &self.timer
+        };
+        self.total_rows += self.rows as u64;
+        self.statements += 1;
+        self.rows = 0;
+        Some((
+            sql,
+            Params::Positional(std::mem::take(&mut self.params)),
+            timer,
+        ))
+    }
+
+    pub fn timing(&self, server: u8) -> InsertTiming {
+        InsertTiming {
+            server,
+            table: self.table.clone(),
+            method: "insert",
+            rows: self.total_rows,
+            statements: self.statements,
+            batch_size: self.max_rows,
+            elapsed: self.sample_timer.elapsed() + self.timer.elapsed(),
+            sample_rows: self.sample_rows.min(self.total_rows),
+            sample_elapsed: self.sample_timer.elapsed(),
+        }
+    }
+}
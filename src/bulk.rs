@@ -0,0 +1,185 @@
+use anyhow::{Context, Result};
+use mysql::{LocalInfileHandler, Value};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backup::sql_literal;
+use crate::report::InsertTiming;
+
+// ============ Bulk load (LOAD DATA LOCAL INFILE) ============
+
+/// Ghi row nguồn (đã remap ID) ra file TSV theo định dạng mặc định của LOAD DATA:
+/// tab giữa các cột, `\n` cuối dòng, escape bằng `\`, NULL là `\N`.
+pub struct BulkWriter {
+    file: BulkFile,
+    writer: BufWriter<File>,
+}
+
+/// File TSV đã ghi xong, chờ nạp vào bảng đích
+#[derive(Debug)]
+pub struct BulkFile {
+    pub server: u8,
+    pub table: String,
+    pub columns: Vec<String>,
+    /// Cột BIT: LOAD DATA không nạp thẳng được từ text, đọc vào biến rồi CAST
+    pub bit_columns: Vec<String>,
+    pub path: PathBuf,
+    pub rows: u64,
+}
+
+impl BulkWriter {
+    pub fn create(
+        dir: &Path,
+        server: u8,
+        table: &str,
+        columns: &[&str],
+        bit_columns: &[String],
+    ) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Không thể tạo thư mục bulk load {}", dir.display()))?;
+        // LOAD DATA LOCAL đọc file theo đường dẫn phía client, dùng đường dẫn tuyệt đối
+        let path = dir
+            .canonicalize()?
+            .join(format!("{}_s{}.tsv", table, server));
+        let file = File::create(&path)
+            .with_context(|| format!("Không thể tạo file {}", path.display()))?;
+        Ok(Self {
+            file: BulkFile {
+                server,
+                table: table.to_string(),
+                columns: columns.iter().map(|c| c.to_string()).collect(),
+                bit_columns: bit_columns.to_vec(),
+                path,
+                rows: 0,
+            },
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn push(&mut self, row: &[Value]) -> Result<()> {
+        for (i, value) in row.iter().enumerate() {
+            if i > 0 {
+                self.writer.write_all(b"\t")?;
+            }
+            write_field(&mut self.writer, value)?;
+        }
+        self.writer.write_all(b"\n")?;
+        self.file.rows += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<BulkFile> {
+        self.writer.flush()?;
+        Ok(self.file)
+    }
+}
+
+impl BulkFile {
+    pub fn load_sql(&self) -> String {
+        let mut sets = Vec::new();
+        let columns = self
+            .columns
+            .iter()
+            .map(|c| {
+                if self.bit_columns.contains(c) {
+                    let var = format!("@bit_{}", sets.len());
+                    sets.push(format!("`{}` = CAST({} AS UNSIGNED)", c, var));
+                    var
+                } else {
+                    format!("`{}`", c)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut sql = format!(
+            "LOAD DATA LOCAL INFILE {} INTO TABLE `{}` CHARACTER SET utf8mb4 \
+             FIELDS TERMINATED BY '\\t' ESCAPED BY '\\\\' LINES TERMINATED BY '\\n' ({})",
+            sql_literal(&Value::from(self.path.to_string_lossy().as_ref())),
+            self.table,
+            columns
+        );
+        if !sets.is_empty() {
+            sql.push_str(&format!(" SET {}", sets.join(", ")));
+        }
+        sql
+    }
+
+    /// `elapsed`: thời gian chạy câu LOAD DATA trên đích
+    pub fn timing(&self, elapsed: Duration) -> InsertTiming {
+        InsertTiming {
+            server: self.server,
+            table: self.table.clone(),
+            method: "load_data",
+            rows: self.rows,
+            statements: 1,
+            batch_size: 0,
+            elapsed,
+            sample_rows: 0,
+            sample_elapsed: Duration::ZERO,
+        }
+    }
+}
+
+/// Handler LOAD DATA LOCAL INFILE cho pool đích: stream file server yêu cầu từ đĩa.
+/// Chỉ phục vụ file nằm trong `dir` (bulk_directory), đường dẫn khác bị từ chối.
+pub fn local_infile_handler(dir: &Path) -> Result<LocalInfileHandler> {
+    let dir = std::env::current_dir()?.join(dir);
+    Ok(LocalInfileHandler::new(move |file_name, writer| {
+        let allowed = dir.canonicalize()?;
+        let requested = std::str::from_utf8(file_name)
+            .map(Path::new)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .canonicalize()?;
+        if !requested.starts_with(&allowed) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "LOAD DATA LOCAL chỉ được đọc file trong {}, từ chối {}",
+                    allowed.display(),
+                    requested.display()
+                ),
+            ));
+        }
+        io::copy(&mut File::open(&requested)?, writer)?;
+        Ok(())
+    }))
+}
+
+fn write_field(out: &mut impl Write, value: &Value) -> io::Result<()> {
+    match value {
+        Value::NULL => out.write_all(b"\\N"),
+        Value::Bytes(bytes) => {
+            for &b in bytes {
+                match b {
+                    b'\\' => out.write_all(b"\\\\")?,
+                    b'\t' => out.write_all(b"\\t")?,
+                    b'\n' => out.write_all(b"\\n")?,
+                    b'\r' => out.write_all(b"\\r")?,
+                    0 => out.write_all(b"\\0")?,
+                    _ => out.write_all(&[b])?,
+                }
+            }
+            Ok(())
+        }
+        Value::Int(v) => write!(out, "{}", v),
+        Value::UInt(v) => write!(out, "{}", v),
+        Value::Float(v) => write!(out, "{}", v),
+        Value::Double(v) => write!(out, "{}", v),
+        Value::Date(year, month, day, hour, minute, second, micros) => write!(
+            out,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+            year, month, day, hour, minute, second, micros
+        ),
+        Value::Time(negative, days, hours, minutes, seconds, micros) => write!(
+            out,
+            "{}{:02}:{:02}:{:02}.{:06}",
+            if *negative { "-" } else { "" },
+            days * 24 + u32::from(*hours),
+            minutes,
+            seconds,
+            micros
+        ),
+    }
+}
@@ -0,0 +1,511 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::json_path::JsonPath;
+
+// ============ Config Structures ============
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// Kiểu cấu hình cũ: 2 server, chọn đích bằng `merge.target_server`
+    pub server1: Option<DatabaseConfig>,
+    pub server2: Option<DatabaseConfig>,
+    /// Kiểu cấu hình nhiều server: 1 `[target]` + nhiều `[[sources]]`
+    pub target: Option<ServerConfig>,
+    #[serde(default)]
+    pub sources: Vec<ServerConfig>,
+    pub merge: MergeConfig,
+    /// Bổ sung / ghi đè các bảng mặc định trong registry (xem `Config::tables`)
+    #[serde(default)]
+    pub tables: Vec<TableConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DatabaseConfig {
+    pub host: String,
+    pub port: u16,
+    pub database: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ServerConfig {
+    /// Số server trong game (dùng cho `clan_sv{n}`, hậu tố đổi tên...)
+    pub server: u8,
+    /// Offset riêng cho server nguồn này, bỏ trống thì dùng `merge.id_offset`
+    pub id_offset: Option<i32>,
+    #[serde(flatten)]
+    pub db: DatabaseConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeConfig {
+    pub id_offset: IdOffsetSetting,
+    /// Khoảng trống cộng thêm khi tính offset tự động
+    #[serde(default = "default_id_offset_margin")]
+    pub id_offset_margin: i32,
+    /// Offset tự động được làm tròn lên bội số của giá trị này
+    #[serde(default = "default_id_offset_round_to")]
+    pub id_offset_round_to: i32,
+    /// Chỉ dùng với kiểu cấu hình `server1` / `server2`
+    pub target_server: Option<u8>,
+    pub backup_before_merge: bool,
+    pub backup_directory: String,
+    #[serde(default = "default_report_directory")]
+    pub report_directory: String,
+    #[serde(default)]
+    pub username_conflict: UsernameConflictPolicy,
+    /// Hậu tố thêm vào username bị trùng, `{server}` được thay bằng số server
+    #[serde(default = "default_rename_suffix")]
+    pub username_suffix: String,
+    #[serde(default)]
+    pub player_name_conflict: NameConflictRule,
+    /// Hậu tố cho tên nhân vật bị trùng (rule server_suffix / force_rename)
+    #[serde(default = "default_rename_suffix")]
+    pub player_name_suffix: String,
+    /// Cột trên bảng player đánh dấu nhân vật phải đổi tên ở lần login tới (rule force_rename)
+    #[serde(default = "default_force_rename_column")]
+    pub force_rename_column: String,
+    /// Chỉ nhận server_suffix hoặc numeric_suffix
+    #[serde(default)]
+    pub clan_name_conflict: NameConflictRule,
+    #[serde(default = "default_rename_suffix")]
+    pub clan_name_suffix: String,
+    /// Cột `clan_id_sv{n}` của server khác server đích trên bảng player
+    #[serde(default)]
+    pub other_clan_columns: OtherClanColumns,
+    /// Giá trị "không có clan" khi `other_clan_columns = "reset"`
+    #[serde(default = "default_clan_reset_value")]
+    pub clan_reset_value: i64,
+    /// Commit sau từng bước và ghi checkpoint để chạy tiếp bằng `--resume <run-id>`
+    #[serde(default)]
+    pub resumable: bool,
+    /// Số row tối đa trong 1 câu INSERT nhiều row (account, gift_code_histories, player_vip)
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Thêm vào đích các cột chỉ có ở nguồn (ADD COLUMN theo định nghĩa của nguồn)
+    /// ở bước chuẩn bị schema, thay vì dừng merge
+    #[serde(default)]
+    pub align_schema: bool,
+    /// Số bước merge độc lập được chạy song song (1 = tuần tự như trước)
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Bảng `strategy = "rows"` được nạp bằng LOAD DATA LOCAL INFILE thay vì INSERT
+    #[serde(default)]
+    pub bulk_load_tables: Vec<String>,
+    /// Thư mục chứa file TSV tạm khi bulk load
+    #[serde(default = "default_bulk_directory")]
+    pub bulk_directory: String,
+}
+
+/// 1 bảng trong registry merge. `{target}` trong tên bảng / tên cột được thay bằng số server đích.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TableConfig {
+    pub name: String,
+    #[serde(default = "default_primary_key")]
+    pub primary_key: String,
+    /// Bảng gốc của 1 loại ID (vd: `player`): khóa chính được cộng offset
+    /// và mapping ID cũ -> mới được lưu vào merge_id_map
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entity: Option<String>,
+    /// Cột lưu ID cũ của bảng entity, được tạo ở bước chuẩn bị schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_id_column: Option<String>,
+    /// Cột tham chiếu -> loại ID, vd: `{ player_id = "player" }`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub references: BTreeMap<String, String>,
+    #[serde(default)]
+    pub strategy: CopyStrategy,
+    /// Chỉ chép các cột này. Mặc định: mọi cột có ở cả nguồn và đích
+    /// (bảng không phải entity thì bỏ khóa chính để đích tự sinh, trừ khi nó là cột tham chiếu)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub columns: Option<Vec<String>>,
+    /// ID nằm trong cột JSON -> loại ID, vd: `{ "members[*].id" = "player" }`.
+    /// ID không có trong mapping được giữ nguyên và ghi vào report.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub json_references: BTreeMap<String, String>,
+    /// Cột được đặt lại giá trị cố định thay vì chép từ nguồn, vd: `{ clan_id_sv2 = -1 }`
+    /// (tham chiếu tới dữ liệu không được merge). Số row bị đặt lại được ghi vào report.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reset_columns: BTreeMap<String, i64>,
+    /// Thứ tự merge (nhỏ chạy trước), bảng phụ thuộc mapping của bảng khác luôn chạy sau bảng đó
+    #[serde(default = "default_table_order")]
+    pub order: i32,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+impl TableConfig {
+    /// Bảng chép từng row với cấu hình mặc định
+    pub fn new(name: String, order: i32) -> Self {
+        Self {
+            name,
+            primary_key: default_primary_key(),
+            entity: None,
+            old_id_column: None,
+            references: BTreeMap::new(),
+            strategy: CopyStrategy::Rows,
+            columns: None,
+            json_references: BTreeMap::new(),
+            reset_columns: BTreeMap::new(),
+            order,
+            enabled: true,
+        }
+    }
+
+    /// Bảng clan của 1 server (`clan_sv{n}`): entity `clan` của server đích hoặc `clan_sv{n}`
+    pub fn is_clan(&self) -> bool {
+        self.entity
+            .as_deref()
+            .is_some_and(|entity| entity == "clan" || entity.starts_with("clan_sv"))
+    }
+}
+
+/// Cách chép dữ liệu 1 bảng từ nguồn sang đích
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyStrategy {
+    /// Stream từng row, remap tham chiếu theo mapping của bảng entity đã merge
+    /// rồi INSERT nhiều row theo `batch_size`
+    #[default]
+    Rows,
+    /// Như `rows` nhưng nạp bằng LOAD DATA LOCAL INFILE
+    LoadData,
+    /// Chép vào temp table rồi remap bằng UPDATE (cộng offset cho giá trị > 0)
+    /// và `INSERT ... SELECT`, nhanh khi nguồn và đích cùng MySQL instance.
+    /// Chỉ dùng cho bảng entity
+    TempTable,
+}
+
+/// `id_offset = 50000` hoặc `id_offset = "auto"`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum IdOffsetSetting {
+    Fixed(i32),
+    Keyword(String),
+}
+
+/// Cách xử lý khi cùng 1 username tồn tại ở nhiều server
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsernameConflictPolicy {
+    /// Đổi tên account của server nguồn: `name` -> `name_s2`
+    #[default]
+    Suffix,
+    /// Account có tongnap (rồi tới lần login cuối) cao hơn giữ tên, account còn lại bị đổi tên
+    KeepHigher,
+    /// Ghi danh sách trùng ra report và dừng merge
+    Abort,
+}
+
+/// Cách đổi tên khi trùng tên nhân vật / clan giữa các server
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NameConflictRule {
+    /// `name` -> `name_s2`
+    #[default]
+    ServerSuffix,
+    /// `name` -> `name_2`, `name_3`...
+    NumericSuffix,
+    /// Đổi tạm như server_suffix và bật cột đánh dấu để game bắt đổi tên khi login
+    ForceRename,
+}
+
+/// Cách xử lý cột `clan_id_sv{n}` (n khác server đích) trên bảng player
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OtherClanColumns {
+    /// Merge cả bảng `clan_sv{n}` và remap cột theo mapping riêng của bảng đó
+    #[default]
+    Merge,
+    /// Đặt cột về `clan_reset_value`, bảng `clan_sv{n}` không được merge
+    Reset,
+}
+
+/// Cột `clan_id_sv{n}` tìm thấy trên bảng player của đích
+#[derive(Debug, Clone, Copy)]
+pub struct ClanColumn {
+    pub server: u8,
+    /// Đích có bảng `clan_sv{n}` tương ứng
+    pub has_table: bool,
+}
+
+fn default_clan_reset_value() -> i64 {
+    -1
+}
+
+fn default_force_rename_column() -> String {
+    "force_rename".to_string()
+}
+
+fn default_report_directory() -> String {
+    "./report".to_string()
+}
+
+fn default_rename_suffix() -> String {
+    "_s{server}".to_string()
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_bulk_directory() -> String {
+    "./bulk".to_string()
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_primary_key() -> String {
+    "id".to_string()
+}
+
+fn default_table_order() -> i32 {
+    100
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_id_offset_margin() -> i32 {
+    1000
+}
+
+fn default_id_offset_round_to() -> i32 {
+    10000
+}
+
+impl DatabaseConfig {
+    /// `host:port/database`
+    pub fn describe(&self) -> String {
+        format!("{}:{}/{}", self.host, self.port, self.database)
+    }
+}
+
+impl Config {
+    /// Server đích và danh sách server nguồn (theo thứ tự merge) cho cả 2 kiểu cấu hình
+    pub fn servers(&self) -> Result<(ServerConfig, Vec<ServerConfig>)> {
+        if let Some(target) = &self.target {
+            if self.sources.is_empty() {
+                bail!("Cấu hình [target] cần ít nhất 1 [[sources]]");
+            }
+            let mut seen = HashSet::from([target.server]);
+            for source in &self.sources {
+                if !seen.insert(source.server) {
+                    bail!("Số server {} bị khai báo nhiều lần", source.server);
+                }
+            }
+            return Ok((target.clone(), self.sources.clone()));
+        }
+
+        let (Some(server1), Some(server2)) = (&self.server1, &self.server2) else {
+            bail!("Config cần [server1] + [server2] hoặc [target] + [[sources]]");
+        };
+        let as_server = |server: u8, db: &DatabaseConfig| ServerConfig {
+            server,
+            id_offset: None,
+            db: db.clone(),
+        };
+        match self.merge.target_server.unwrap_or(1) {
+            1 => Ok((as_server(1, server1), vec![as_server(2, server2)])),
+            2 => Ok((as_server(2, server2), vec![as_server(1, server1)])),
+            n => bail!("target_server không hợp lệ: {} (chỉ nhận 1 hoặc 2)", n),
+        }
+    }
+
+    /// Cấu hình kết nối của server theo số server
+    pub fn server_db(&self, server: u8) -> Result<DatabaseConfig> {
+        let (target, sources) = self.servers()?;
+        match std::iter::once(target)
+            .chain(sources)
+            .find(|s| s.server == server)
+        {
+            Some(found) => Ok(found.db),
+            None => bail!("Không có server {} trong config", server),
+        }
+    }
+
+    /// Cột tool tự thêm vào bảng đích (bảng, cột): `old_id_column` của entity
+    /// và cột đánh dấu bắt đổi tên của player khi dùng rule force_rename
+    pub fn tool_columns(&self, tables: &[TableConfig]) -> Vec<(String, String)> {
+        let mut columns: Vec<(String, String)> = tables
+            .iter()
+            .filter_map(|t| t.old_id_column.clone().map(|c| (t.name.clone(), c)))
+            .collect();
+        if self.merge.player_name_conflict == NameConflictRule::ForceRename {
+            columns.push(("player".to_string(), self.merge.force_rename_column.clone()));
+        }
+        columns
+    }
+
+    /// Registry bảng merge theo thứ tự chạy: các bảng mặc định của tool, mục `[[tables]]`
+    /// trùng tên thì thay thế hẳn mục mặc định, tên mới thì được thêm vào.
+    /// `clan_columns` là các cột `clan_id_sv{n}` có trên bảng player của đích.
+    pub fn tables(
+        &self,
+        target_server: u8,
+        clan_columns: &[ClanColumn],
+    ) -> Result<Vec<TableConfig>> {
+        let resolve = |name: &str| name.replace("{target}", &target_server.to_string());
+
+        let mut tables = builtin_tables(target_server, clan_columns, &self.merge);
+        for table in &self.tables {
+            let mut table = table.clone();
+            table.name = resolve(&table.name);
+            table.references = table
+                .references
+                .iter()
+                .map(|(column, entity)| (resolve(column), entity.clone()))
+                .collect();
+            table.reset_columns = table
+                .reset_columns
+                .iter()
+                .map(|(column, value)| (resolve(column), *value))
+                .collect();
+            table.json_references = table
+                .json_references
+                .iter()
+                .map(|(path, entity)| (resolve(path), entity.clone()))
+                .collect();
+            match tables.iter_mut().find(|t| t.name == table.name) {
+                Some(existing) => *existing = table,
+                None => tables.push(table),
+            }
+        }
+        tables.retain(|t| t.enabled);
+        tables.sort_by_key(|t| t.order);
+
+        let mut entities = HashSet::new();
+        for table in &tables {
+            if let Some(entity) = &table.entity {
+                if entity.len() > 16 {
+                    bail!("entity \"{}\" dài quá 16 ký tự", entity);
+                }
+                if !entities.insert(entity.as_str()) {
+                    bail!("entity \"{}\" được khai báo ở nhiều bảng", entity);
+                }
+                if let Some(columns) = &table.columns {
+                    if !columns.contains(&table.primary_key) {
+                        bail!(
+                            "Bảng {} là entity nên columns phải có khóa chính {}",
+                            table.name,
+                            table.primary_key
+                        );
+                    }
+                }
+            } else if table.old_id_column.is_some() {
+                bail!(
+                    "Bảng {} có old_id_column nhưng không phải entity",
+                    table.name
+                );
+            }
+        }
+        for table in &tables {
+            if let Some(column) = table
+                .reset_columns
+                .keys()
+                .find(|c| table.references.contains_key(*c))
+            {
+                bail!(
+                    "Bảng {}: cột {} vừa có trong references vừa trong reset_columns",
+                    table.name,
+                    column
+                );
+            }
+            for path in table.json_references.keys() {
+                JsonPath::parse(path)?;
+            }
+            // Temp table chép bỏ AUTO_INCREMENT của khóa chính và xác định row bằng khóa chính
+            // mới, chỉ bảng entity (khóa chính được cộng offset) mới dùng được
+            if table.entity.is_none() && table.strategy == CopyStrategy::TempTable {
+                bail!(
+                    "Bảng {}: strategy temp_table chỉ dùng cho bảng entity, dùng rows hoặc load_data",
+                    table.name
+                );
+            }
+            for (column, entity) in table.references.iter().chain(&table.json_references) {
+                if !entities.contains(entity.as_str()) {
+                    bail!(
+                        "Bảng {}: cột {} tham chiếu entity \"{}\" không có trong registry",
+                        table.name,
+                        column,
+                        entity
+                    );
+                }
+            }
+        }
+        Ok(tables)
+    }
+}
+
+/// Các bảng tool merge sẵn, có thể ghi đè bằng `[[tables]]` cùng tên.
+/// Mỗi cột `clan_id_sv{n}` của server khác đích được remap theo bảng `clan_sv{n}`
+/// (merge thêm bảng đó) hoặc đặt lại theo `other_clan_columns`.
+fn builtin_tables(
+    target_server: u8,
+    clan_columns: &[ClanColumn],
+    merge: &MergeConfig,
+) -> Vec<TableConfig> {
+    let table = TableConfig::new;
+
+    let mut player = TableConfig {
+        entity: Some("player".to_string()),
+        old_id_column: Some("old_id".to_string()),
+        strategy: CopyStrategy::TempTable,
+        ..table("player".to_string(), 20)
+    };
+    player
+        .references
+        .insert("account_id".to_string(), "account".to_string());
+    player
+        .references
+        .insert(format!("clan_id_sv{}", target_server), "clan".to_string());
+
+    // Danh sách thành viên clan lưu player ID trong JSON
+    let clan_json = || BTreeMap::from([("members[*].id".to_string(), "player".to_string())]);
+    let mut clans = vec![TableConfig {
+        entity: Some("clan".to_string()),
+        json_references: clan_json(),
+        strategy: CopyStrategy::TempTable,
+        ..table(format!("clan_sv{}", target_server), 30)
+    }];
+    for clan in clan_columns.iter().filter(|c| c.server != target_server) {
+        let column = format!("clan_id_sv{}", clan.server);
+        let name = format!("clan_sv{}", clan.server);
+        // Không có bảng clan tương ứng thì không remap được, luôn đặt lại
+        if merge.other_clan_columns == OtherClanColumns::Reset || !clan.has_table {
+            player.reset_columns.insert(column, merge.clan_reset_value);
+            continue;
+        }
+        player.references.insert(column, name.clone());
+        clans.push(TableConfig {
+            entity: Some(name.clone()),
+            json_references: clan_json(),
+            strategy: CopyStrategy::TempTable,
+            ..table(name, 30)
+        });
+    }
+
+    let mut tables = vec![
+        TableConfig {
+            entity: Some("account".to_string()),
+            old_id_column: Some("old_id".to_string()),
+            ..table("account".to_string(), 10)
+        },
+        player,
+    ];
+    tables.extend(clans);
+    for (name, order) in [("gift_code_histories", 40), ("player_vip", 50)] {
+        let mut extra = table(name.to_string(), order);
+        extra
+            .references
+            .insert("player_id".to_string(), "player".to_string());
+        tables.push(extra);
+    }
+    tables
+}
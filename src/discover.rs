@@ -0,0 +1,218 @@
+use anyhow::Result;
+use mysql::prelude::*;
+use mysql::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::TableConfig;
+
+// Chỉ cột kiểu số nguyên mới có thể là ID tham chiếu
+const ID_TYPES: [&str; 5] = ["tinyint", "smallint", "mediumint", "int", "bigint"];
+
+// ============ Discover ============
+
+/// Cột có vẻ tham chiếu tới 1 bảng gốc (account / player / clan_sv{n}...)
+#[derive(Debug)]
+pub struct DiscoveredReference {
+    pub table: String,
+    pub column: String,
+    /// Bảng được tham chiếu
+    pub referenced_table: String,
+    /// Entity trong registry ứng với bảng được tham chiếu (None: bảng đó không được merge như entity)
+    pub entity: Option<String>,
+    /// Có FOREIGN KEY khai báo trong schema (ngược lại là đoán theo tên cột)
+    pub foreign_key: bool,
+    pub status: ReferenceStatus,
+}
+
+/// Cột tham chiếu đã được registry remap hay chưa
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReferenceStatus {
+    /// Bảng có trong registry và cột đã khai báo trong `references`
+    Covered,
+    /// Bảng có trong registry nhưng thiếu cột trong `references`
+    MissingReference,
+    /// Bảng không có trong registry, dữ liệu của bảng sẽ không được merge
+    TableNotMerged,
+    /// Bảng được tham chiếu không phải entity nên không remap được
+    NotAnEntity,
+    /// Cột được đặt lại giá trị cố định theo `reset_columns`
+    Reset,
+}
+
+/// Kết quả quét schema
+#[derive(Debug)]
+pub struct Discovery {
+    pub references: Vec<DiscoveredReference>,
+    /// Bảng không có trong registry và không có cột tham chiếu nào
+    pub unmerged_tables: Vec<String>,
+    /// Bảng trong registry nhưng không có trong database
+    pub missing_tables: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct StarterConfig<'a> {
+    tables: &'a [TableConfig],
+}
+
+/// Quét INFORMATION_SCHEMA của database đang kết nối và đối chiếu với registry.
+/// FOREIGN KEY khai báo trong schema được ưu tiên, còn lại đoán theo tên cột:
+/// `<entity>_id` / `<bảng entity>_id` và `clan_id_sv{n}` -> `clan_sv{n}`.
+pub fn discover(
+    conn: &mut PooledConn,
+    tables: &[TableConfig],
+    ignored_tables: &[&str],
+) -> Result<Discovery> {
+    let columns: Vec<(String, String, String)> = conn.query(
+        "SELECT TABLE_NAME, COLUMN_NAME, DATA_TYPE FROM INFORMATION_SCHEMA.COLUMNS
+         WHERE TABLE_SCHEMA = DATABASE()
+         ORDER BY TABLE_NAME, ORDINAL_POSITION",
+    )?;
+    let foreign_keys: HashMap<(String, String), String> = conn
+        .query::<(String, String, String), _>(
+            "SELECT TABLE_NAME, COLUMN_NAME, REFERENCED_TABLE_NAME
+             FROM INFORMATION_SCHEMA.KEY_COLUMN_USAGE
+             WHERE TABLE_SCHEMA = DATABASE() AND REFERENCED_TABLE_NAME IS NOT NULL",
+        )?
+        .into_iter()
+        .map(|(table, column, referenced)| ((table, column), referenced))
+        .collect();
+
+    // Bảng entity theo tên bảng và tên entity
+    let entity_tables: HashMap<&str, &str> = tables
+        .iter()
+        .filter_map(|t| t.entity.as_deref().map(|e| (t.name.as_str(), e)))
+        .collect();
+    let existing: Vec<&str> = {
+        let mut names: Vec<&str> = columns.iter().map(|(t, _, _)| t.as_str()).collect();
+        names.dedup();
+        names
+    };
+
+    let mut references = Vec::new();
+    for (table, column, data_type) in &columns {
+        if ignored_tables.contains(&table.as_str()) {
+            continue;
+        }
+        let foreign_key = foreign_keys.get(&(table.clone(), column.clone()));
+        let referenced_table = match foreign_key {
+            Some(referenced) => Some(referenced.clone()),
+            None if ID_TYPES.contains(&data_type.as_str()) => {
+                guess_referenced_table(column, &entity_tables)
+                    .filter(|referenced| existing.contains(&referenced.as_str()))
+            }
+            None => None,
+        };
+        let Some(referenced_table) = referenced_table else {
+            continue;
+        };
+        // Khóa chính của chính bảng entity
+        if *referenced_table == *table {
+            continue;
+        }
+
+        let entity = entity_tables
+            .get(referenced_table.as_str())
+            .map(|e| e.to_string());
+        let registered = tables.iter().find(|t| t.name == *table);
+        let status = match (&entity, registered) {
+            (_, Some(registered)) if registered.reset_columns.contains_key(column) => {
+                ReferenceStatus::Reset
+            }
+            (None, _) => ReferenceStatus::NotAnEntity,
+            (Some(_), None) => ReferenceStatus::TableNotMerged,
+            (Some(entity), Some(registered)) => {
+                if registered.references.get(column) == Some(entity) {
+                    ReferenceStatus::Covered
+                } else {
+                    ReferenceStatus::MissingReference
+                }
+            }
+        };
+        references.push(DiscoveredReference {
+            table: table.clone(),
+            column: column.clone(),
+            referenced_table,
+            entity,
+            foreign_key: foreign_key.is_some(),
+            status,
+        });
+    }
+
+    let unmerged_tables = existing
+        .iter()
+        .filter(|t| !ignored_tables.contains(t))
+        .filter(|t| !tables.iter().any(|r| r.name == **t))
+        .filter(|t| !references.iter().any(|r| r.table == **t))
+        .map(|t| t.to_string())
+        .collect();
+    let missing_tables = tables
+        .iter()
+        .filter(|t| !existing.contains(&t.name.as_str()))
+        .map(|t| t.name.clone())
+        .collect();
+
+    Ok(Discovery {
+        references,
+        unmerged_tables,
+        missing_tables,
+    })
+}
+
+/// Bảng mà tên cột có vẻ tham chiếu tới
+fn guess_referenced_table(column: &str, entity_tables: &HashMap<&str, &str>) -> Option<String> {
+    if let Some(server) = column.strip_prefix("clan_id_sv") {
+        if !server.is_empty() && server.chars().all(|c| c.is_ascii_digit()) {
+            return Some(format!("clan_sv{}", server));
+        }
+    }
+    let prefix = column.strip_suffix("_id")?;
+    entity_tables
+        .iter()
+        .find(|(table, entity)| **table == prefix || **entity == prefix)
+        .map(|(table, _)| table.to_string())
+}
+
+/// Các mục `[[tables]]` bổ sung cột tham chiếu còn thiếu: bảng chưa có trong registry
+/// được thêm mới (order từ 100), bảng đã có thì chép lại cấu hình kèm references đầy đủ.
+pub fn starter_tables(discovery: &Discovery, tables: &[TableConfig]) -> Vec<TableConfig> {
+    let mut missing: BTreeMap<&str, Vec<&DiscoveredReference>> = BTreeMap::new();
+    for reference in &discovery.references {
+        if matches!(
+            reference.status,
+            ReferenceStatus::MissingReference | ReferenceStatus::TableNotMerged
+        ) {
+            missing
+                .entry(reference.table.as_str())
+                .or_default()
+                .push(reference);
+        }
+    }
+
+    let mut next_order = 100;
+    missing
+        .into_iter()
+        .map(|(name, references)| {
+            let mut table = match tables.iter().find(|t| t.name == name) {
+                Some(existing) => existing.clone(),
+                None => {
+                    next_order += 10;
+                    TableConfig::new(name.to_string(), next_order - 10)
+                }
+            };
+            for reference in references {
+                if let Some(entity) = &reference.entity {
+                    table
+                        .references
+                        .insert(reference.column.clone(), entity.clone());
+                }
+            }
+            table
+        })
+        .collect()
+}
+
+/// Nội dung TOML của các mục `[[tables]]`, để chép vào config
+pub fn starter_config(tables: &[TableConfig]) -> Result<String> {
+    Ok(toml::to_string_pretty(&StarterConfig { tables })?)
+}
@@ -0,0 +1,330 @@
+use anyhow::{bail, Result};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+
+// ============ JSON path ============
+
+/// Vị trí ID nằm trong cột JSON: tên cột rồi tới đường dẫn, vd: `members[*].id`,
+/// `leader_id`, `messages[*].player_id`. Phần tử là chuỗi chứa JSON (vd: members dạng
+/// mảng các chuỗi) được parse khi đi qua và ghi lại đúng dạng chuỗi.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    pub path: String,
+    pub column: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    /// `[*]`: mọi phần tử của mảng
+    All,
+}
+
+/// Giá trị trên path không đổi được, được giữ nguyên
+#[derive(Debug, PartialEq)]
+pub struct UnmappedValue {
+    pub path: String,
+    pub value: String,
+    pub reason: UnmappedReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmappedReason {
+    /// ID không có trong mapping của entity
+    NotInMapping,
+    /// Chuỗi nằm trên path nhưng không phải JSON
+    InvalidJson,
+}
+
+impl UnmappedReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnmappedReason::NotInMapping => "not_in_mapping",
+            UnmappedReason::InvalidJson => "invalid_json",
+        }
+    }
+}
+
+/// Đổi ID theo mapping của 1 path, ghi lại giá trị không đổi được
+struct Remapper<'a> {
+    path: &'a str,
+    mapping: Option<&'a HashMap<i32, i32>>,
+    unmapped: &'a mut Vec<UnmappedValue>,
+}
+
+impl Remapper<'_> {
+    fn remap(&mut self, id: i64) -> Option<i64> {
+        // Giá trị <= 0: không tham chiếu ai (vd: -1)
+        if id <= 0 {
+            return None;
+        }
+        let new_id = i32::try_from(id)
+            .ok()
+            .and_then(|id| self.mapping.and_then(|m| m.get(&id)))
+            .map(|&new_id| i64::from(new_id));
+        if new_id.is_none() {
+            self.report(id.to_string(), UnmappedReason::NotInMapping);
+        }
+        new_id
+    }
+
+    fn report(&mut self, value: String, reason: UnmappedReason) {
+        self.unmapped.push(UnmappedValue {
+            path: self.path.to_string(),
+            value,
+            reason,
+        });
+    }
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<Self> {
+        let column_end = path.find(['.', '[']).unwrap_or(path.len());
+        let column = &path[..column_end];
+        if column.is_empty() {
+            bail!("JSON path \"{}\" thiếu tên cột", path);
+        }
+
+        let mut segments = Vec::new();
+        let mut rest = &path[column_end..];
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('[') {
+                let Some(end) = after.find(']') else {
+                    bail!("JSON path \"{}\" thiếu ]", path);
+                };
+                segments.push(match &after[..end] {
+                    "*" => Segment::All,
+                    index => match index.parse() {
+                        Ok(index) => Segment::Index(index),
+                        Err(_) => bail!("JSON path \"{}\": [{}] không hợp lệ", path, index),
+                    },
+                });
+                rest = &after[end + 1..];
+            } else if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                if end == 0 {
+                    bail!("JSON path \"{}\" có key rỗng", path);
+                }
+                segments.push(Segment::Key(after[..end].to_string()));
+                rest = &after[end..];
+            } else {
+                bail!("JSON path \"{}\" không hợp lệ", path);
+            }
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            column: column.to_string(),
+            segments,
+        })
+    }
+}
+
+/// Đổi các ID trong giá trị cột theo `paths` (cùng 1 cột), mỗi path kèm mapping của entity
+/// (None: entity chưa có mapping). ID không có trong mapping và chuỗi không phải JSON nằm
+/// trên path được giữ nguyên và đưa vào `unmapped`, giá trị <= 0 (không tham chiếu ai,
+/// vd: -1) được bỏ qua. Trả về None nếu không có gì thay đổi.
+pub fn remap_column(
+    json_str: &str,
+    paths: &[(JsonPath, Option<&HashMap<i32, i32>>)],
+    unmapped: &mut Vec<UnmappedValue>,
+) -> Result<Option<String>> {
+    if json_str.trim().is_empty() {
+        return Ok(None);
+    }
+    let mut value: JsonValue = serde_json::from_str(json_str)?;
+    let mut changed = false;
+    for (path, mapping) in paths {
+        let mut remapper = Remapper {
+            path: &path.path,
+            mapping: *mapping,
+            unmapped: &mut *unmapped,
+        };
+        changed |= remap_value(&mut value, &path.segments, &mut remapper)?;
+    }
+    if !changed {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::to_string(&value)?))
+}
+
+fn remap_value(
+    value: &mut JsonValue,
+    segments: &[Segment],
+    remapper: &mut Remapper,
+) -> Result<bool> {
+    // Chuỗi chứa JSON: parse, đổi bên trong rồi ghi lại thành chuỗi
+    if let JsonValue::String(inner) = value {
+        if !segments.is_empty() {
+            let Ok(mut parsed) = serde_json::from_str::<JsonValue>(inner) else {
+                // Không phải JSON: cấu trúc không khớp path, giữ nguyên
+                remapper.report(inner.clone(), UnmappedReason::InvalidJson);
+                return Ok(false);
+            };
+            let changed = remap_value(&mut parsed, segments, remapper)?;
+            if changed {
+                *inner = serde_json::to_string(&parsed)?;
+            }
+            return Ok(changed);
+        }
+    }
+
+    let Some((segment, rest)) = segments.split_first() else {
+        // Tới vị trí ID: giữ nguyên kiểu số / chuỗi số
+        let new_id = match value {
+            JsonValue::Number(n) => n
+                .as_i64()
+                .and_then(|id| remapper.remap(id))
+                .map(JsonValue::from),
+            JsonValue::String(s) => s
+                .parse::<i64>()
+                .ok()
+                .and_then(|id| remapper.remap(id))
+                .map(|id| JsonValue::from(id.to_string())),
+            _ => None,
+        };
+        return Ok(match new_id {
+            Some(new_id) => {
+                *value = new_id;
+                true
+            }
+            None => false,
+        });
+    };
+
+    let mut changed = false;
+    match (segment, value) {
+        (Segment::Key(key), JsonValue::Object(map)) => {
+            if let Some(child) = map.get_mut(key) {
+                changed = remap_value(child, rest, remapper)?;
+            }
+        }
+        (Segment::Index(index), JsonValue::Array(items)) => {
+            if let Some(child) = items.get_mut(*index) {
+                changed = remap_value(child, rest, remapper)?;
+            }
+        }
+        (Segment::All, JsonValue::Array(items)) => {
+            for child in items {
+                changed |= remap_value(child, rest, remapper)?;
+            }
+        }
+        // Cấu trúc không khớp path (vd: members rỗng / null): không có gì để đổi
+        _ => {}
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping() -> HashMap<i32, i32> {
+        HashMap::from([(1, 1001), (2, 1002)])
+    }
+
+    fn remap(
+        json: &str,
+        path: &str,
+        mapping: &HashMap<i32, i32>,
+    ) -> (Option<String>, Vec<UnmappedValue>) {
+        let mut unmapped = Vec::new();
+        let paths = [(JsonPath::parse(path).unwrap(), Some(mapping))];
+        let updated = remap_column(json, &paths, &mut unmapped).unwrap();
+        (updated, unmapped)
+    }
+
+    #[test]
+    fn parse_splits_column_and_segments() {
+        let path = JsonPath::parse("members[*].id").unwrap();
+        assert_eq!(path.column, "members");
+        assert_eq!(
+            path.segments,
+            vec![Segment::All, Segment::Key("id".to_string())]
+        );
+
+        let path = JsonPath::parse("info.leaders[0]").unwrap();
+        assert_eq!(path.column, "info");
+        assert_eq!(
+            path.segments,
+            vec![Segment::Key("leaders".to_string()), Segment::Index(0)]
+        );
+
+        assert!(JsonPath::parse("leader_id").unwrap().segments.is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_paths() {
+        for path in ["", ".id", "members[*", "members[x]", "info..id"] {
+            assert!(JsonPath::parse(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn remaps_every_array_member() {
+        let (updated, unmapped) = remap(r#"[{"id":1},{"id":2}]"#, "members[*].id", &mapping());
+        assert_eq!(updated.as_deref(), Some(r#"[{"id":1001},{"id":1002}]"#));
+        assert!(unmapped.is_empty());
+    }
+
+    #[test]
+    fn remaps_only_the_indexed_member() {
+        let (updated, _) = remap(r#"[{"id":1},{"id":2}]"#, "members[1].id", &mapping());
+        assert_eq!(updated.as_deref(), Some(r#"[{"id":1},{"id":1002}]"#));
+    }
+
+    #[test]
+    fn remaps_string_wrapped_members_and_keeps_them_as_strings() {
+        let json = r#"["{\"id\":1,\"name\":\"a\"}","{\"id\":\"2\"}"]"#;
+        let (updated, unmapped) = remap(json, "members[*].id", &mapping());
+        assert_eq!(
+            updated.as_deref(),
+            Some(r#"["{\"id\":1001,\"name\":\"a\"}","{\"id\":\"1002\"}"]"#)
+        );
+        assert!(unmapped.is_empty());
+    }
+
+    #[test]
+    fn skips_values_not_referencing_anything() {
+        let (updated, unmapped) = remap(r#"[{"id":0},{"id":-1}]"#, "members[*].id", &mapping());
+        assert_eq!(updated, None);
+        assert!(unmapped.is_empty());
+    }
+
+    #[test]
+    fn reports_ids_missing_from_mapping() {
+        let (updated, unmapped) = remap(r#"[{"id":1},{"id":7}]"#, "members[*].id", &mapping());
+        assert_eq!(updated.as_deref(), Some(r#"[{"id":1001},{"id":7}]"#));
+        assert_eq!(
+            unmapped,
+            vec![UnmappedValue {
+                path: "members[*].id".to_string(),
+                value: "7".to_string(),
+                reason: UnmappedReason::NotInMapping,
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_strings_that_are_not_json() {
+        let (updated, unmapped) = remap(r#"["{\"id\":1}","oops"]"#, "members[*].id", &mapping());
+        assert_eq!(updated.as_deref(), Some(r#"["{\"id\":1001}","oops"]"#));
+        assert_eq!(
+            unmapped,
+            vec![UnmappedValue {
+                path: "members[*].id".to_string(),
+                value: "oops".to_string(),
+                reason: UnmappedReason::InvalidJson,
+            }]
+        );
+    }
+
+    #[test]
+    fn ignores_structure_not_matching_path() {
+        let (updated, unmapped) = remap(r#"{"members":null}"#, "info.members[*].id", &mapping());
+        assert_eq!(updated, None);
+        assert!(unmapped.is_empty());
+    }
+}
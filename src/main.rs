@@ -1,127 +1,423 @@
-use anyhow::{Context, Result};
-use clap::Parser;
+mod backup;
+mod batch;
+mod bulk;
+mod config;
+mod discover;
+mod json_path;
+mod report;
+mod schema;
+mod script;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
 use log::info;
 use mysql::prelude::*;
 use mysql::*;
-use serde::Deserialize;
-use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::path::Path;
 
-// ============ Config Structures ============
-
-#[derive(Debug, Deserialize)]
-struct Config {
-    server1: DatabaseConfig,
-    server2: DatabaseConfig,
-    merge: MergeConfig,
-}
-
-#[derive(Debug, Deserialize)]
-struct DatabaseConfig {
-    host: String,
-    port: u16,
-    database: String,
-    username: String,
-    password: String,
+use batch::{ExecTimer, InsertBatch, PER_ROW_SAMPLE};
+use bulk::{BulkFile, BulkWriter};
+use config::{
+    ClanColumn, Config, CopyStrategy, DatabaseConfig, IdOffsetSetting, NameConflictRule,
+    ServerConfig, TableConfig, UsernameConflictPolicy,
+};
+use discover::ReferenceStatus;
+use json_path::JsonPath;
+use report::InsertTiming;
+use report::{
+    AccountRename, ClanRename, ColumnReset, IdMapping, MergeReport, PlayerRename, SourceSummary,
+    UnmappedJsonId,
+};
+use schema::{SchemaDifference, Severity};
+use script::SqlScript;
+use std::cell::RefCell;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Số row mỗi câu INSERT khi stream dữ liệu giữa 2 MySQL instance khác nhau
+const STREAM_BATCH_ROWS: usize = 500;
+
+// Bảng lưu mapping ID cũ -> ID mới ở database đích
+const ID_MAP_TABLE: &str = "merge_id_map";
+
+// Bảng lưu các bước merge đã commit (dùng cho --resume)
+const CHECKPOINT_TABLE: &str = "merge_checkpoint";
+
+// Bảng lưu tên đã đổi của các bước đã commit, để report của lần resume vẫn đủ
+const RENAME_TABLE: &str = "merge_rename";
+
+// Số warning tối đa in ra khi LOAD DATA có warning
+const LOAD_WARNING_LIMIT: usize = 10;
+
+// net_write_timeout (giây) cho kết nối nguồn của worker song song: worker có thể bị
+// chặn lâu khi hàng đợi ghi đầy trong lúc thread ghi chạy câu lệnh lớn
+const WORKER_NET_WRITE_TIMEOUT_SECS: u32 = 24 * 3600;
+
+// JSON path trong cột kèm mapping của entity được tham chiếu (None: chưa có mapping)
+type JsonReference<'a> = (JsonPath, Option<&'a HashMap<i32, i32>>);
+
+// Câu ghi do worker tạo ra, chạy lần lượt trên kết nối đích chính
+enum QueuedWrite {
+    /// Câu lệnh kèm timer của batch (nếu có) để đo thời gian chạy ở thread ghi
+    Sql(String, Params, Option<ExecTimer>),
+    Load(BulkFile),
+    /// Batch INSERT của 1 bảng đã gửi hết câu lệnh: ghi timing sau khi các câu trước đã chạy
+    Timing(u8, InsertBatch),
 }
 
-#[derive(Debug, Deserialize)]
-struct MergeConfig {
-    id_offset: i32,
-    target_server: u8,
-    // backup_before_merge: bool,
-    // backup_directory: String,
-    // batch_size: usize,
+thread_local! {
+    // Có giá trị khi thread đang là worker của `merge_parallel`: câu ghi được đẩy vào
+    // hàng đợi thay vì chạy trên kết nối đích của worker (chỉ dùng để đọc)
+    static WRITE_QUEUE: RefCell<Option<SyncSender<QueuedWrite>>> = const { RefCell::new(None) };
 }
 
 // ============ CLI Arguments ============
 
 #[derive(Parser, Debug)]
 #[command(name = "DB Merge Tool")]
-#[command(about = "Tool merge 2 database game server thành 1", long_about = None)]
+#[command(about = "Tool merge nhiều database game server thành 1", long_about = None)]
 struct Args {
     /// Đường dẫn đến file config
     #[arg(short, long, default_value = "config.toml")]
     config: String,
 
-    /// Chế độ dry-run (không thực sự merge, chỉ kiểm tra)
-    #[arg(long, default_value_t = false)]
-    dry_run: bool,
+    /// Chế độ dry-run: `plan` (mặc định) chỉ kiểm tra, không ghi gì;
+    /// `full` chạy merge thật trong transaction rồi luôn rollback
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "plan")]
+    dry_run: Option<DryRunMode>,
 
     /// Bỏ qua backup
     #[arg(long, default_value_t = false)]
     skip_backup: bool,
+
+    /// Chạy tiếp lần merge bị dừng giữa chừng, bỏ qua các bước đã commit
+    #[arg(long, value_name = "RUN_ID")]
+    resume: Option<String>,
+
+    /// Không ghi vào database đích, chỉ xuất toàn bộ câu lệnh merge ra file SQL để review
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["dry_run", "resume"])]
+    emit_sql: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum DryRunMode {
+    /// Chỉ đọc dữ liệu, lập kế hoạch (đổi tên, offset...), không ghi gì
+    Plan,
+    /// Chạy toàn bộ merge + verify trong 1 transaction trên đích rồi rollback
+    /// (AUTO_INCREMENT được đặt lại). Dừng nếu schema đích còn cần thay đổi
+    /// (DDL commit ngầm, không rollback được)
+    Full,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Khôi phục database từ thư mục backup do tool tạo ra
+    Restore {
+        /// Thư mục backup (vd: ./backup/20240101_120000)
+        #[arg(long)]
+        from: String,
+
+        /// Server cần khôi phục (mặc định: server đích trong config)
+        #[arg(long)]
+        server: Option<u8>,
+    },
+    /// So sánh schema các bảng merge giữa nguồn và đích (cột, kiểu, NULL, DEFAULT, charset, index)
+    SchemaDiff {
+        /// Chỉ so sánh server nguồn này (mặc định: mọi server nguồn)
+        #[arg(long)]
+        server: Option<u8>,
+
+        /// Ghi report dạng CSV
+        #[arg(long, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// Quét schema database đích tìm các cột tham chiếu account / player / clan
+    /// và các bảng chưa có trong registry merge
+    Discover {
+        /// Ghi các mục [[tables]] còn thiếu ra file TOML để bổ sung vào config
+        #[arg(long, value_name = "FILE")]
+        write_config: Option<String>,
+    },
 }
 
 // ============ Main Application ============
 
+/// Các bước merge được checkpoint khi chạy ở chế độ resumable
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MergeStep {
+    /// Đổi username account sẵn có ở đích (chạy 1 lần, trước mọi nguồn)
+    TargetRenames,
+    /// Merge 1 bảng trong registry (vị trí trong `MergeTool::tables`)
+    Table(usize),
+}
+
+/// Trạng thái merge của 1 server nguồn
+struct SourceServer {
+    config: ServerConfig,
+    pool: Pool,
+    /// Offset thực tế sau khi resolve (cấu hình tay hoặc auto)
+    id_offset: i32,
+    /// Nguồn và đích cùng MySQL instance (dùng được query chéo schema)
+    same_instance: bool,
+    /// Mapping ID cũ -> mới theo entity (account / player / clan...)
+    mappings: HashMap<String, HashMap<i32, i32>>,
+    /// Username mới của account nguồn (theo ID cũ) khi bị trùng
+    username_renames: HashMap<i32, String>,
+    /// Tên mới của nhân vật nguồn (theo ID cũ) khi bị trùng
+    player_renames: HashMap<i32, String>,
+    /// Tên mới của clan nguồn (theo bảng clan_sv{n}, ID cũ) khi bị trùng
+    clan_renames: HashMap<String, HashMap<i32, String>>,
+}
+
 struct MergeTool {
     config: Config,
-    server1_pool: Pool,
-    server2_pool: Pool,
-    account_mapping: HashMap<i32, i32>,
-    player_mapping: HashMap<i32, i32>,
-    clan_mapping: HashMap<i32, i32>,
+    target: ServerConfig,
+    target_pool: Pool,
+    sources: Vec<SourceServer>,
+    /// Registry bảng merge, đã sắp theo thứ tự chạy
+    tables: Vec<TableConfig>,
+    /// Account sẵn có ở đích bị đổi username (policy keep_higher)
+    target_username_renames: Vec<(i32, String)>,
+    report: MergeReport,
+    run_id: String,
+    /// Thư mục con trong report_directory (lần resume ghi vào thư mục riêng)
+    report_id: String,
+    /// Dry-run plan: bỏ qua mọi câu ghi
     dry_run: bool,
+    /// Dry-run full: ghi thật trong transaction nhưng luôn rollback
+    rollback_only: bool,
+    /// --emit-sql: câu lệnh ghi được chép vào file thay vì chạy trên đích
+    script: Option<Mutex<SqlScript>>,
+    emit_sql: Option<String>,
+    /// Thời gian insert theo bảng, chuyển vào report sau khi merge
+    insert_timings: Mutex<Vec<InsertTiming>>,
+    /// ID trong cột JSON không có trong mapping, chuyển vào report sau khi merge
+    unmapped_json_ids: Mutex<Vec<UnmappedJsonId>>,
+    skip_backup: bool,
+    /// Mỗi bước commit riêng và ghi checkpoint, chạy lại được bằng --resume
+    resumable: bool,
+    /// Đang chạy tiếp 1 lần merge cũ
+    resuming: bool,
+    /// Các bước đã commit: (server nguồn, bước)
+    completed_steps: HashSet<(u8, String)>,
+    /// ID offset đã dùng ở lần chạy trước, theo server nguồn
+    resumed_offsets: HashMap<u8, i32>,
+}
+
+impl SourceServer {
+    fn mapping(&self, entity: &str) -> Option<&HashMap<i32, i32>> {
+        self.mappings.get(entity)
+    }
+
+    fn mapped_count(&self, entity: &str) -> usize {
+        self.mapping(entity).map_or(0, HashMap::len)
+    }
 }
 
 impl MergeTool {
-    fn new(config: Config, dry_run: bool) -> Result<Self> {
-        info!("Đang kết nối đến database Server 1...");
-        let server1_pool = Self::create_pool(&config.server1)?;
+    fn new(
+        config: Config,
+        dry_run: Option<DryRunMode>,
+        skip_backup: bool,
+        resume: Option<String>,
+        emit_sql: Option<String>,
+    ) -> Result<Self> {
+        let (target, source_configs) = config.servers()?;
+        let rollback_only = dry_run == Some(DryRunMode::Full);
+        if rollback_only && resume.is_some() {
+            bail!("--dry-run=full không dùng cùng --resume");
+        }
+
+        info!(
+            "Đang kết nối đến database Server {} (đích)...",
+            target.server
+        );
+        // LOAD DATA LOCAL cần handler để gửi nội dung file, thiếu handler driver gửi file rỗng
+        let target_pool = Pool::new(Self::pool_opts(&target.db).local_infile_handler(Some(
+            bulk::local_infile_handler(Path::new(&config.merge.bulk_directory))?,
+        )))
+        .context("Không thể kết nối database")?;
+        let clan_columns = Self::detect_clan_columns(&mut target_pool.get_conn()?)?;
+        let tables = config.tables(target.server, &clan_columns)?;
+
+        let mut sources = Vec::new();
+        for source in source_configs {
+            info!("Đang kết nối đến database Server {}...", source.server);
+            sources.push(SourceServer {
+                pool: Self::create_pool(&source.db)?,
+                config: source,
+                id_offset: 0,
+                same_instance: false,
+                mappings: HashMap::new(),
+                username_renames: HashMap::new(),
+                player_renames: HashMap::new(),
+                clan_renames: HashMap::new(),
+            });
+        }
 
-        info!("Đang kết nối đến database Server 2...");
-        let server2_pool = Self::create_pool(&config.server2)?;
+        let now = chrono::Local::now().format("%Y%m%d_%H%M%S").to_string();
+        let (run_id, report_id) = match &resume {
+            Some(run_id) => (run_id.clone(), format!("{}_resume_{}", run_id, now)),
+            None => (now.clone(), now),
+        };
 
         Ok(Self {
+            // Dry-run full phải nằm trọn trong 1 transaction để rollback được
+            resumable: (config.merge.resumable || resume.is_some())
+                && !rollback_only
+                && emit_sql.is_none(),
             config,
-            server1_pool,
-            server2_pool,
-            account_mapping: HashMap::new(),
-            player_mapping: HashMap::new(),
-            clan_mapping: HashMap::new(),
-            dry_run,
+            target,
+            target_pool,
+            sources,
+            tables,
+            target_username_renames: Vec::new(),
+            report: MergeReport::default(),
+            run_id,
+            report_id,
+            dry_run: dry_run == Some(DryRunMode::Plan),
+            rollback_only,
+            script: None,
+            emit_sql,
+            insert_timings: Mutex::new(Vec::new()),
+            unmapped_json_ids: Mutex::new(Vec::new()),
+            skip_backup,
+            resuming: resume.is_some(),
+            completed_steps: HashSet::new(),
+            resumed_offsets: HashMap::new(),
         })
     }
 
     fn create_pool(db_config: &DatabaseConfig) -> Result<Pool> {
-        let opts = OptsBuilder::new()
+        Pool::new(Self::pool_opts(db_config)).context("Không thể kết nối database")
+    }
+
+    fn pool_opts(db_config: &DatabaseConfig) -> OptsBuilder {
+        OptsBuilder::new()
             .ip_or_hostname(Some(&db_config.host))
             .tcp_port(db_config.port)
             .db_name(Some(&db_config.database))
             .user(Some(&db_config.username))
-            .pass(Some(&db_config.password));
+            .pass(Some(&db_config.password))
+    }
+
+    /// Các cột `clan_id_sv{n}` trên bảng player của đích (mỗi server cũ có 1 bảng `clan_sv{n}`)
+    fn detect_clan_columns(conn: &mut PooledConn) -> Result<Vec<ClanColumn>> {
+        let columns: Vec<String> = conn.query(
+            "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'player'
+               AND COLUMN_NAME LIKE 'clan\\_id\\_sv%'",
+        )?;
+        let tables: Vec<String> = conn.query(
+            "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME LIKE 'clan\\_sv%'",
+        )?;
 
-        Pool::new(opts).context("Không thể kết nối database")
+        let mut clan_columns: Vec<ClanColumn> = columns
+            .iter()
+            .filter_map(|c| c.strip_prefix("clan_id_sv")?.parse().ok())
+            .map(|server: u8| ClanColumn {
+                server,
+                has_table: tables.contains(&format!("clan_sv{}", server)),
+            })
+            .collect();
+        clan_columns.sort_by_key(|c| c.server);
+        Ok(clan_columns)
     }
 
     fn execute(&mut self) -> Result<()> {
+        // 0. Xác định ID offset trước khi làm bất cứ điều gì
+        if self.resuming {
+            self.load_checkpoints()?;
+        }
+        self.check_source_entity_tables()?;
+        self.resolve_id_offsets()?;
+        self.detect_same_instances()?;
+
         println!(
             "\n{}",
-            "=== BẮT ĐẦU MERGE 2 SERVER ===".bright_cyan().bold()
+            format!("=== BẮT ĐẦU MERGE {} SERVER ===", self.sources.len() + 1)
+                .bright_cyan()
+                .bold()
+        );
+        println!(
+            "Server đích: {} ({}) {}",
+            self.target.server,
+            self.target.db.describe(),
+            "<- dữ liệu sẽ được GHI vào database này".red().bold()
         );
-        println!("Server đích: {}", self.config.merge.target_server);
-        println!("ID Offset: {}", self.config.merge.id_offset);
+        for source in &self.sources {
+            println!(
+                "Server nguồn: {} ({}) | ID offset: {}{} | {}",
+                source.config.server,
+                source.config.db.describe(),
+                source.id_offset,
+                match (&source.config.id_offset, &self.config.merge.id_offset) {
+                    (None, IdOffsetSetting::Keyword(_)) => " (auto)",
+                    _ => "",
+                },
+                if source.same_instance {
+                    "cùng MySQL instance (copy trực tiếp qua schema)"
+                } else {
+                    "khác MySQL instance (stream dữ liệu từ nguồn sang đích)"
+                }
+            );
+        }
         println!(
             "Mode: {}",
             if self.dry_run {
                 "DRY RUN (không commit)".yellow()
+            } else if self.rollback_only {
+                "DRY RUN FULL (chạy merge thật trong transaction rồi rollback)".yellow()
+            } else if self.emit_sql.is_some() {
+                "EMIT SQL (chỉ xuất file SQL, không ghi vào database đích)".yellow()
+            } else if self.resumable {
+                "PRODUCTION, commit sau từng bước (resumable)".red()
             } else {
                 "PRODUCTION (sẽ commit)".red()
             }
         );
+        println!("Run ID: {}", self.run_id);
+        if self.workers() > 1 {
+            println!(
+                "Workers: {} (đọc nguồn song song, ghi đích trong 1 kết nối)",
+                self.workers()
+            );
+        }
         println!();
 
         // 1. Thống kê trước merge
         self.print_statistics()?;
 
-        // 2. Xác nhận từ user
-        if !self.dry_run {
+        // 2. Pre-flight: trùng ID / tràn INT ở các bảng bị remap, trùng username
+        self.check_bulk_load()?;
+        if !self.dry_run && self.emit_sql.is_none() {
+            // Commit kiểm tra transaction qua INNODB_TRX, thiếu quyền thì dừng ngay từ đây
+            Self::transaction_active(&mut self.target_pool.get_conn()?)?;
+        }
+        self.check_schema()?;
+        self.preflight_check()?;
+        self.plan_username_renames()?;
+        self.plan_player_renames()?;
+        self.plan_clan_renames()?;
+        self.plan_column_resets()?;
+
+        // --emit-sql: ghi toàn bộ câu lệnh ra file, không cần xác nhận / backup
+        if let Some(path) = self.emit_sql.clone() {
+            return self.emit_script(Path::new(&path));
+        }
+
+        // 3. Xác nhận từ user
+        if !self.dry_run && !self.rollback_only {
             println!("\n{} Bạn có muốn tiếp tục merge? (yes/no): ", "⚠️".yellow());
             let mut input = String::new();
             io::stdin().read_line(&mut input)?;
@@ -131,140 +427,2306 @@ impl MergeTool {
             }
         }
 
-        // 3. Bắt đầu transaction
-        let mut server1_conn = self.server1_pool.get_conn()?;
-        let mut server2_conn = self.server2_pool.get_conn()?;
+        // 4. Backup trước khi ghi dữ liệu (dry-run full luôn rollback nên không cần)
+        if !self.dry_run && !self.rollback_only {
+            if self.resuming {
+                println!(
+                    "\n{} Bỏ qua backup khi resume (dùng backup của lần chạy {})",
+                    "⚠".yellow(),
+                    self.run_id
+                );
+            } else if self.skip_backup {
+                println!(
+                    "\n{} {}",
+                    "⚠".yellow(),
+                    "Bỏ qua backup theo --skip-backup!".red().bold()
+                );
+            } else if !self.config.merge.backup_before_merge {
+                println!(
+                    "\n{} {}",
+                    "⚠".yellow(),
+                    "backup_before_merge = false, KHÔNG backup trước khi merge!"
+                        .red()
+                        .bold()
+                );
+            } else {
+                self.run_backup()
+                    .context("Backup thất bại, dừng merge (chưa có thay đổi nào được ghi)")?;
+            }
+        }
 
+        // 5. Chuẩn bị schema (DDL commit ngầm nên phải xong trước khi mở transaction)
+        let mut target_conn = self.target_pool.get_conn()?;
+        if !self.prepare_schema(&mut target_conn)? {
+            println!("Đã hủy merge.");
+            return Ok(());
+        }
         if !self.dry_run {
-            server1_conn.query_drop("START TRANSACTION")?;
-            server2_conn.query_drop("START TRANSACTION")?;
+            self.create_temp_tables(&mut target_conn)?;
         }
 
-        // 4. Thực hiện merge
-        let result = self.run_merge(&mut server1_conn, &mut server2_conn);
+        // 6. Bắt đầu transaction (chế độ resumable thì mỗi bước tự mở transaction riêng)
+        let mut saved_auto_increments = Vec::new();
+        if !self.dry_run && !self.resumable {
+            saved_auto_increments = self.save_auto_increments(&mut target_conn)?;
+            target_conn.query_drop("START TRANSACTION")?;
+        }
 
-        // 5. Commit hoặc rollback
-        match result {
-            Ok(_) => {
+        // 7. Thực hiện merge, 8. commit hoặc rollback (lỗi lúc commit cũng phải rollback)
+        let result = self
+            .run_merge(&mut target_conn)
+            .and_then(|_| self.finish_merge(&mut target_conn, &saved_auto_increments));
+        let result = match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
                 if self.dry_run {
-                    println!("\n{}", "✓ DRY RUN hoàn thành".green().bold());
+                    return Err(e);
+                }
+                // Lỗi sau COMMIT (vd: ALTER AUTO_INCREMENT) thì không còn gì để rollback
+                let open =
+                    !self.resumable && Self::transaction_active(&mut target_conn).unwrap_or(true);
+                if open {
+                    self.rollback_merge(&mut target_conn, &saved_auto_increments)?;
                 } else {
-                    println!("\n{} Bạn có muốn COMMIT thay đổi? (yes/no): ", "⚠".yellow());
-                    let mut input = String::new();
-                    io::stdin().read_line(&mut input)?;
-
-                    if input.trim().to_lowercase() == "yes" {
-                        server1_conn.query_drop("COMMIT")?;
-                        server2_conn.query_drop("COMMIT")?;
-                        println!("\n{}", "=== MERGE THÀNH CÔNG ===".green().bold());
-                    } else {
-                        server1_conn.query_drop("ROLLBACK")?;
-                        server2_conn.query_drop("ROLLBACK")?;
-                        println!("\n{}", "Đã rollback tất cả thay đổi".yellow());
-                    }
+                    target_conn.query_drop("ROLLBACK")?;
                 }
-                Ok(())
-            }
-            Err(e) => {
-                if !self.dry_run {
-                    server1_conn.query_drop("ROLLBACK")?;
-                    server2_conn.query_drop("ROLLBACK")?;
+                if self.resumable {
+                    // Các bước đã commit vẫn phải có report (tên đã đổi, mapping)
+                    self.collect_report();
+                    if let Err(report_error) = self.write_report() {
+                        println!("{} Không ghi được report: {}", "⚠".yellow(), report_error);
+                    }
+                    println!(
+                        "\n{} Các bước đã commit được giữ lại, chạy tiếp bằng: --resume {}",
+                        "⚠".yellow(),
+                        self.run_id
+                    );
                 }
                 Err(e)
             }
+        };
+
+        if !self.dry_run {
+            self.drop_temp_tables(&mut target_conn)?;
         }
+        result
     }
 
-    fn run_merge(
-        &mut self,
+    /// Kết thúc merge đã chạy xong: ghi report, commit hoặc rollback theo mode
+    fn finish_merge(
+        &self,
         target_conn: &mut PooledConn,
-        source_conn: &mut PooledConn,
+        saved_auto_increments: &[(String, i64)],
     ) -> Result<()> {
-        // Tắt foreign key check tạm thời
-        target_conn.query_drop("SET FOREIGN_KEY_CHECKS=0")?;
+        if self.dry_run {
+            self.write_report()?;
+            println!("\n{}", "✓ DRY RUN hoàn thành".green().bold());
+        } else if self.rollback_only {
+            Self::ensure_transaction_open(target_conn)?;
+            self.print_merge_result(target_conn)?;
+            let restored = self.rollback_merge(target_conn, saved_auto_increments)?;
+            self.write_report()?;
+            if restored {
+                println!(
+                    "\n{}",
+                    "✓ DRY RUN FULL hoàn thành, đã rollback tất cả thay đổi"
+                        .green()
+                        .bold()
+                );
+            } else {
+                println!(
+                    "\n{}",
+                    "✓ DRY RUN FULL hoàn thành, đã rollback dữ liệu nhưng AUTO_INCREMENT các bảng trên đã bị đổi"
+                        .yellow()
+                        .bold()
+                );
+            }
+        } else if self.resumable {
+            // Dữ liệu đã commit: ghi report trước, lỗi AUTO_INCREMENT không làm mất report
+            self.write_report()?;
+            self.ensure_auto_increments(target_conn)?;
+            println!("\n{}", "=== MERGE THÀNH CÔNG ===".green().bold());
+        } else {
+            Self::ensure_transaction_open(target_conn)?;
+            println!("\n{} Bạn có muốn COMMIT thay đổi? (yes/no): ", "⚠".yellow());
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
 
-        // Tạo cột old_id nếu chưa có
-        self.ensure_old_id_columns(target_conn)?;
+            if input.trim().to_lowercase() == "yes" {
+                target_conn.query_drop("COMMIT")?;
+                self.write_report()?;
+                self.ensure_auto_increments(target_conn)?;
+                println!("\n{}", "=== MERGE THÀNH CÔNG ===".green().bold());
+            } else {
+                if self.rollback_merge(target_conn, saved_auto_increments)? {
+                    println!("\n{}", "Đã rollback tất cả thay đổi".yellow());
+                } else {
+                    println!(
+                        "\n{}",
+                        "Đã rollback dữ liệu, AUTO_INCREMENT các bảng trên đã bị đổi".yellow()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 
-        // Merge theo thứ tự
-        self.merge_accounts(target_conn, source_conn)?;
-        self.merge_players(target_conn, source_conn)?;
-        self.merge_clans(target_conn, source_conn)?;
-        self.merge_gift_code_histories(target_conn, source_conn)?;
-        self.merge_other_tables(target_conn, source_conn)?;
+    /// Ghi toàn bộ các bước merge (schema, temp table, dữ liệu, mapping) vào file SQL
+    /// theo đúng thứ tự sẽ chạy. Database đích chỉ được đọc, không bị thay đổi.
+    fn emit_script(&mut self, path: &Path) -> Result<()> {
+        println!(
+            "\n{}",
+            format!(">>> Xuất câu lệnh merge ra {}...", path.display()).bright_yellow()
+        );
 
-        // Bật lại foreign key check
-        target_conn.query_drop("SET FOREIGN_KEY_CHECKS=1")?;
+        let mut header = vec![
+            "Script merge sinh bởi DB Merge Tool (--emit-sql)".to_string(),
+            format!("Run ID: {}", self.run_id),
+            format!("Tạo lúc: {}", chrono::Local::now().to_rfc3339()),
+            format!(
+                "Server đích: {} ({})",
+                self.target.server,
+                self.target.db.describe()
+            ),
+        ];
+        for source in &self.sources {
+            header.push(format!(
+                "Server nguồn: {} ({}) | ID offset: {}",
+                source.config.server,
+                source.config.db.describe(),
+                source.id_offset
+            ));
+        }
+        header.extend([
+            format!(
+                "username_conflict = {:?}, player_name_conflict = {:?}, clan_name_conflict = {:?}",
+                self.config.merge.username_conflict,
+                self.config.merge.player_name_conflict,
+                self.config.merge.clan_name_conflict
+            ),
+            String::new(),
+            "Chạy script trên database đích bằng 1 session duy nhất (temp table theo session).".to_string(),
+            "Phần CHUẨN BỊ SCHEMA (ALTER / CREATE TABLE) commit ngầm, phần dữ liệu nằm trong 1 transaction.".to_string(),
+            "Dữ liệu nguồn được chép dưới dạng INSERT, script không cần kết nối tới server nguồn.".to_string(),
+            "Sau khi apply nên kiểm tra AUTO_INCREMENT của account, player, clan và player không có account.".to_string(),
+        ]);
+        self.script = Some(Mutex::new(SqlScript::create(path, &header)?));
+
+        let mut target_conn = self.target_pool.get_conn()?;
+
+        self.script_comment("=== CHUẨN BỊ SCHEMA ===")?;
+        self.prepare_schema(&mut target_conn)?;
+        self.create_temp_tables(&mut target_conn)?;
+
+        self.script_comment("=== MERGE DỮ LIỆU ===")?;
+        self.write_sql(&mut target_conn, "START TRANSACTION", ())?;
+        self.run_merge(&mut target_conn)?;
+        self.write_sql(&mut target_conn, "COMMIT", ())?;
+        self.drop_temp_tables(&mut target_conn)?;
+
+        let (path, statements) = self.script.take().unwrap().into_inner().unwrap().finish()?;
+        self.write_report()?;
+        println!(
+            "\n{} Đã ghi {} câu lệnh vào {} (database đích không bị thay đổi)",
+            "✓".green(),
+            statements,
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Chạy câu lệnh ghi trên đích, hoặc chép vào file SQL khi --emit-sql
+    fn write_sql(
+        &self,
+        conn: &mut PooledConn,
+        sql: impl AsRef<str>,
+        params: impl Into<Params>,
+    ) -> Result<()> {
+        self.write_timed(conn, sql, params, None)
+    }
 
-        // Verify
-        self.verify_merge(target_conn)?;
+    /// Như `write_sql`, cộng thời gian chạy câu lệnh vào `timer`
+    fn write_timed(
+        &self,
+        conn: &mut PooledConn,
+        sql: impl AsRef<str>,
+        params: impl Into<Params>,
+        timer: Option<&ExecTimer>,
+    ) -> Result<()> {
+        let params = params.into();
+        if let Some(script) = &self.script {
+            return script.lock().unwrap().statement(sql.as_ref(), params);
+        }
+        if Self::in_worker() {
+            return Self::enqueue_write(QueuedWrite::Sql(
+                sql.as_ref().to_string(),
+                params,
+                timer.cloned(),
+            ));
+        }
+        let run = || match params {
+            Params::Empty => conn.query_drop(sql.as_ref()),
+            params => conn.exec_drop(sql.as_ref(), params),
+        };
+        match timer {
+            Some(timer) => timer.time(run)?,
+            None => run()?,
+        }
+        Ok(())
+    }
 
+    fn write_batch<P: Into<Params>>(
+        &self,
+        conn: &mut PooledConn,
+        sql: impl AsRef<str>,
+        params: impl IntoIterator<Item = P>,
+    ) -> Result<()> {
+        if let Some(script) = &self.script {
+            let mut script = script.lock().unwrap();
+            for p in params {
+                script.statement(sql.as_ref(), p.into())?;
+            }
+            return Ok(());
+        }
+        if Self::in_worker() {
+            for p in params {
+                self.write_sql(conn, sql.as_ref(), p)?;
+            }
+            return Ok(());
+        }
+        conn.exec_batch(sql.as_ref(), params)?;
         Ok(())
     }
 
-    fn ensure_old_id_columns(&self, conn: &mut PooledConn) -> Result<()> {
-        println!("\n{}", ">>> Kiểm tra và tạo cột old_id...".bright_yellow());
+    /// Thread hiện tại là worker của `merge_parallel` (câu ghi phải đi qua hàng đợi)
+    fn in_worker() -> bool {
+        WRITE_QUEUE.with(|queue| queue.borrow().is_some())
+    }
 
-        // Kiểm tra và thêm cột old_id cho bảng account
-        let account_has_old_id: Option<String> = conn.query_first(
-            "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
-             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'account' AND COLUMN_NAME = 'old_id'",
+    fn enqueue_write(write: QueuedWrite) -> Result<()> {
+        WRITE_QUEUE.with(|queue| {
+            queue
+                .borrow()
+                .as_ref()
+                .context("Thread không có hàng đợi ghi")?
+                .send(write)
+                .map_err(|_| anyhow!("Kết nối ghi đích đã dừng"))
+        })
+    }
+
+    /// Bảng nạp bằng LOAD DATA: `strategy = "load_data"` hoặc có trong `bulk_load_tables`
+    /// (--emit-sql và dry-run plan vẫn dùng INSERT)
+    fn bulk_enabled(&self, table: &TableConfig) -> bool {
+        !self.dry_run
+            && self.script.is_none()
+            && (table.strategy == CopyStrategy::LoadData
+                || self.config.merge.bulk_load_tables.contains(&table.name))
+    }
+
+    /// File TSV cho bảng chọn bulk load, None thì dùng INSERT nhiều row
+    fn bulk_writer(
+        &self,
+        server: u8,
+        table: &TableConfig,
+        columns: &[&str],
+        bit_columns: &[String],
+    ) -> Result<Option<BulkWriter>> {
+        if !self.bulk_enabled(table) {
+            return Ok(None);
+        }
+        let dir = Path::new(&self.config.merge.bulk_directory).join(&self.run_id);
+        Ok(Some(BulkWriter::create(
+            &dir,
+            server,
+            &table.name,
+            columns,
+            bit_columns,
+        )?))
+    }
+
+    /// Kiểm tra `bulk_load_tables` và quyền LOAD DATA LOCAL trên đích trước khi merge
+    fn check_bulk_load(&self) -> Result<()> {
+        // Chỉ bảng chép từng row mới nạp được bằng LOAD DATA
+        let supported: Vec<&str> = self
+            .tables
+            .iter()
+            .filter(|t| t.strategy != CopyStrategy::TempTable)
+            .map(|t| t.name.as_str())
+            .collect();
+        let unsupported: Vec<&String> = self
+            .config
+            .merge
+            .bulk_load_tables
+            .iter()
+            .filter(|t| !supported.contains(&t.as_str()))
+            .collect();
+        if !unsupported.is_empty() {
+            bail!(
+                "bulk_load_tables có bảng không hỗ trợ: {:?} (hỗ trợ: {})",
+                unsupported,
+                supported.join(", ")
+            );
+        }
+
+        let bulk_tables: Vec<&str> = self
+            .tables
+            .iter()
+            .filter(|t| {
+                t.strategy == CopyStrategy::LoadData
+                    || self.config.merge.bulk_load_tables.contains(&t.name)
+            })
+            .map(|t| t.name.as_str())
+            .collect();
+        if bulk_tables.is_empty() || self.dry_run || self.emit_sql.is_some() {
+            return Ok(());
+        }
+
+        let enabled: Option<i64> = self
+            .target_pool
+            .get_conn()?
+            .query_first("SELECT @@GLOBAL.local_infile")?;
+        if enabled.unwrap_or(0) == 0 {
+            bail!(
+                "Database đích đang tắt local_infile, cần SET GLOBAL local_infile = 1 hoặc chuyển {} về INSERT",
+                bulk_tables.join(", ")
+            );
+        }
+        println!(
+            "{} Bulk load (LOAD DATA LOCAL INFILE): {}",
+            "✓".green(),
+            bulk_tables.join(", ")
+        );
+        Ok(())
+    }
+
+    /// Nạp file TSV vào bảng đích và so số row đã nạp với số row trong file.
+    /// File được xóa khi nạp thành công, giữ lại để kiểm tra khi lỗi.
+    fn load_bulk_file(&self, conn: &mut PooledConn, file: BulkFile) -> Result<()> {
+        if Self::in_worker() {
+            return Self::enqueue_write(QueuedWrite::Load(file));
+        }
+
+        let started = Instant::now();
+        conn.query_drop(file.load_sql())
+            .with_context(|| format!("LOAD DATA vào {} thất bại", file.table))?;
+        let elapsed = started.elapsed();
+        let loaded = conn.affected_rows();
+        // LOAD DATA LOCAL ngầm IGNORE: giá trị bị cắt / ép kiểu chỉ hiện qua warning,
+        // coi là lỗi để transaction rollback thay vì commit dữ liệu sai
+        let warning_count = conn.warnings();
+        if warning_count > 0 {
+            let warnings: Vec<(String, u32, String)> =
+                conn.query(format!("SHOW WARNINGS LIMIT {}", LOAD_WARNING_LIMIT))?;
+            let details: Vec<String> = warnings
+                .iter()
+                .map(|(level, code, message)| format!("  {} {}: {}", level, code, message))
+                .collect();
+            bail!(
+                "LOAD DATA vào {} có {} warning (file: {}):\n{}",
+                file.table,
+                warning_count,
+                file.path.display(),
+                details.join("\n")
+            );
+        }
+        if loaded != file.rows {
+            bail!(
+                "LOAD DATA vào {} chỉ nạp {} / {} row (file: {})",
+                file.table,
+                loaded,
+                file.rows,
+                file.path.display()
+            );
+        }
+        fs::remove_file(&file.path)?;
+        self.record_timing(file.timing(elapsed));
+        Ok(())
+    }
+
+    fn script_comment(&self, text: &str) -> Result<()> {
+        if let Some(script) = &self.script {
+            script.lock().unwrap().comment(text)?;
+        }
+        Ok(())
+    }
+
+    /// Các bảng entity có khóa chính bị cộng offset khi merge: (bảng, khóa chính, bước merge)
+    fn remapped_tables(&self) -> Vec<(String, String, MergeStep)> {
+        self.tables
+            .iter()
+            .enumerate()
+            .filter(|(_, table)| table.entity.is_some())
+            .map(|(i, table)| {
+                (
+                    table.name.clone(),
+                    table.primary_key.clone(),
+                    MergeStep::Table(i),
+                )
+            })
+            .collect()
+    }
+
+    /// Bảng entity phải có ở mọi server nguồn (offset và mapping đọc ID từ đó).
+    /// Bảng `clan_sv{n}` được merge theo cột `clan_id_sv{n}` của đích nên nguồn cũ có thể thiếu.
+    fn check_source_entity_tables(&self) -> Result<()> {
+        let mut missing = Vec::new();
+        for source in &self.sources {
+            let mut conn = source.pool.get_conn()?;
+            for table in self.tables.iter().filter(|t| t.entity.is_some()) {
+                if !Self::table_exists(&mut conn, &table.name)? {
+                    println!(
+                        "{} Server {} không có bảng entity {}",
+                        "✗".red(),
+                        source.config.server,
+                        table.name
+                    );
+                    missing.push(table);
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if missing
+            .iter()
+            .any(|t| t.is_clan() && t.entity.as_deref() != Some("clan"))
+        {
+            println!(
+                "  {} Bảng clan_sv{{n}} của server khác: đặt other_clan_columns = \"reset\" để bỏ qua bảng và đặt lại cột clan_id_sv{{n}}",
+                "→".cyan()
+            );
+        }
+        bail!("Server nguồn thiếu bảng entity, không tính được ID offset / mapping");
+    }
+
+    /// Tính offset nhỏ nhất an toàn cho từng server nguồn từ MAX/MIN(id) của các bảng bị remap.
+    /// Các nguồn được xếp vào những dải ID liên tiếp, không chồng lên đích và lên nhau.
+    /// `id_offset = "auto"` thì dùng offset này, còn offset cấu hình tay
+    /// mà làm trùng dải ID thì dừng ngay.
+    fn resolve_id_offsets(&mut self) -> Result<()> {
+        println!("\n{}", ">>> Kiểm tra dải ID...".bright_yellow());
+
+        let tables = self.remapped_tables();
+        let mut target_conn = self.target_pool.get_conn()?;
+
+        // MAX(id) đã bị chiếm của từng bảng: ban đầu là của đích, cộng dồn sau mỗi nguồn
+        let mut used_max: Vec<i64> = Vec::new();
+        for (table, pk, _) in &tables {
+            let max: Option<Option<i64>> =
+                target_conn.query_first(format!("SELECT MAX(`{}`) FROM `{}`", pk, table))?;
+            used_max.push(max.flatten().unwrap_or(0));
+        }
+
+        let margin = i64::from(self.config.merge.id_offset_margin.max(0));
+        let round_to = i64::from(self.config.merge.id_offset_round_to.max(1));
+
+        println!(
+            "{:<8} | {:<25} | {:>12} | {:>12} | {:>12}",
+            "Server", "Bảng", "Đã dùng MAX", "Nguồn MIN", "Nguồn MAX"
+        );
+
+        for source in &mut self.sources {
+            let mut source_conn = source.pool.get_conn()?;
+
+            // (min, max) của từng bảng, None nếu bảng rỗng
+            let mut ranges: Vec<Option<(i64, i64)>> = Vec::new();
+            for ((table, pk, _), used) in tables.iter().zip(&used_max) {
+                let query = format!("SELECT MIN(`{}`), MAX(`{}`) FROM `{}`", pk, pk, table);
+                let (min, max): (Option<i64>, Option<i64>) =
+                    source_conn.query_first(&query)?.unwrap_or((None, None));
+                println!(
+                    "{:<8} | {:<25} | {:>12} | {:>12} | {:>12}",
+                    source.config.server,
+                    table,
+                    used,
+                    min.map_or("-".to_string(), |v| v.to_string()),
+                    max.map_or("-".to_string(), |v| v.to_string())
+                );
+                ranges.push(min.zip(max));
+            }
+
+            // Offset tối thiểu để ID nguồn sau khi cộng vượt qua dải ID đã bị chiếm
+            let required = ranges
+                .iter()
+                .zip(&used_max)
+                .filter_map(|(range, used)| range.map(|(min, _)| used - min + 1))
+                .max()
+                .unwrap_or(0)
+                .max(0);
+            let proposed = (required + margin + round_to - 1) / round_to * round_to;
+
+            // Resume phải dùng lại đúng offset đã dùng cho các bước đã commit
+            let resumed = self.resumed_offsets.get(&source.config.server).copied();
+            let offset = match (
+                resumed.or(source.config.id_offset),
+                &self.config.merge.id_offset,
+            ) {
+                (Some(offset), _) => i64::from(offset),
+                (None, IdOffsetSetting::Fixed(offset)) => i64::from(*offset),
+                (None, IdOffsetSetting::Keyword(keyword)) if keyword == "auto" => proposed,
+                (None, IdOffsetSetting::Keyword(keyword)) => {
+                    bail!(
+                        "id_offset không hợp lệ: \"{}\" (chỉ nhận số nguyên hoặc \"auto\")",
+                        keyword
+                    )
+                }
+            };
+
+            let mut collisions = 0;
+            for (((table, _, _), range), used) in tables.iter().zip(&ranges).zip(&used_max) {
+                let Some((min, max)) = range else {
+                    continue;
+                };
+                // Bảng đã merge ở lần chạy trước thì ID nguồn đã nằm sẵn trong đích
+                let merged = self
+                    .completed_steps
+                    .contains(&(source.config.server, table.clone()));
+                if !merged && min + offset <= *used {
+                    collisions += 1;
+                    println!(
+                        "{} Server {} / {}: ID nguồn nhỏ nhất {} + {} = {} <= MAX(id) đã dùng {}",
+                        "✗".red(),
+                        source.config.server,
+                        table,
+                        min,
+                        offset,
+                        min + offset,
+                        used
+                    );
+                }
+                if max + offset > i64::from(i32::MAX) {
+                    bail!(
+                        "Server {}: id_offset = {} làm ID mới của {} ({}) vượt quá giới hạn INT ({})",
+                        source.config.server,
+                        offset,
+                        table,
+                        max + offset,
+                        i32::MAX
+                    );
+                }
+            }
+            if collisions > 0 {
+                bail!(
+                    "Server {}: id_offset = {} làm trùng ID với server đích hoặc server nguồn trước đó, cần ít nhất {} (hoặc đặt id_offset = \"auto\")",
+                    source.config.server,
+                    offset,
+                    proposed
+                );
+            }
+
+            // Dải ID của nguồn này đã bị chiếm, nguồn sau phải nằm phía trên
+            for (used, range) in used_max.iter_mut().zip(&ranges) {
+                if let Some((_, max)) = range {
+                    *used = (*used).max(max + offset);
+                }
+            }
+
+            source.id_offset = offset as i32;
+            println!(
+                "{} Server {} dùng ID offset: {} (đề xuất: {})",
+                "✓".green(),
+                source.config.server,
+                source.id_offset,
+                proposed
+            );
+        }
+        Ok(())
+    }
+
+    /// So sánh schema các bảng merge của từng nguồn với đích, dừng nếu có khác biệt
+    /// làm merge lỗi hoặc mất dữ liệu
+    fn check_schema(&self) -> Result<()> {
+        println!("\n{}", "=== PRE-FLIGHT KIỂM TRA SCHEMA ===".bright_cyan());
+
+        let mut target_conn = self.target_pool.get_conn()?;
+        let mut source_conns = Vec::new();
+        for source in &self.sources {
+            source_conns.push((source.config.server, source.pool.get_conn()?));
+        }
+        let mut blocking = 0;
+        for (server, differences) in schema::diff_sources(
+            &mut source_conns,
+            &mut target_conn,
+            &self.tables,
+            &self.config.tool_columns(&self.tables),
+            self.config.merge.align_schema,
+        )? {
+            print_schema_differences(server, &differences);
+            blocking += differences
+                .iter()
+                .filter(|d| d.severity == Severity::Blocking)
+                .count();
+        }
+
+        if blocking > 0 {
+            bail!(
+                "Schema nguồn và đích có {} khác biệt chặn merge, chưa có thay đổi nào được ghi",
+                blocking
+            );
+        }
+        Ok(())
+    }
+
+    /// Kiểm tra từng row của các bảng bị remap: `id + offset` không được
+    /// trùng ID đã có ở đích hoặc của server nguồn khác và không được tràn INT.
+    /// Lỗi được liệt kê theo bảng.
+    fn preflight_check(&self) -> Result<()> {
+        println!("\n{}", "=== PRE-FLIGHT KIỂM TRA ID ===".bright_cyan());
+
+        let mut target_conn = self.target_pool.get_conn()?;
+
+        let mut failed_tables = 0;
+        for (table, pk, step) in self.remapped_tables() {
+            // ID mới của các nguồn đã kiểm tra, để phát hiện trùng giữa các nguồn với nhau
+            let mut claimed: HashSet<i64> = HashSet::new();
+
+            for source in &self.sources {
+                if self.is_completed(source.config.server, step) {
+                    continue;
+                }
+                let offset = i64::from(source.id_offset);
+                let source_ids: Vec<i64> = source
+                    .pool
+                    .get_conn()?
+                    .query(format!("SELECT `{}` FROM `{}`", pk, table))?;
+
+                let overflows: Vec<i64> = source_ids
+                    .iter()
+                    .copied()
+                    .filter(|id| i32::try_from(id + offset).is_err())
+                    .collect();
+
+                let target_ids: HashSet<i64> =
+                    match (source_ids.iter().min(), source_ids.iter().max()) {
+                        (Some(min), Some(max)) => target_conn
+                            .exec::<i64, _, _>(
+                                format!(
+                                    "SELECT `{}` FROM `{}` WHERE `{}` BETWEEN ? AND ?",
+                                    pk, table, pk
+                                ),
+                                (min + offset, max + offset),
+                            )?
+                            .into_iter()
+                            .collect(),
+                        _ => HashSet::new(),
+                    };
+                let collisions: Vec<i64> = source_ids
+                    .iter()
+                    .copied()
+                    .filter(|id| {
+                        target_ids.contains(&(id + offset)) || claimed.contains(&(id + offset))
+                    })
+                    .collect();
+                claimed.extend(source_ids.iter().map(|id| id + offset));
+
+                let label = format!("{} (server {})", table, source.config.server);
+                if overflows.is_empty() && collisions.is_empty() {
+                    println!(
+                        "{} {:<35} {:>8} rows, không trùng ID",
+                        "✓".green(),
+                        label,
+                        source_ids.len()
+                    );
+                    continue;
+                }
+
+                failed_tables += 1;
+                println!("{} {}", "✗".red(), label);
+                if !collisions.is_empty() {
+                    println!(
+                        "    {} ID trùng sau khi cộng offset (vd: {})",
+                        collisions.len(),
+                        Self::format_examples(&collisions, offset)
+                    );
+                }
+                if !overflows.is_empty() {
+                    println!(
+                        "    {} ID tràn INT sau khi cộng offset (vd: {})",
+                        overflows.len(),
+                        Self::format_examples(&overflows, offset)
+                    );
+                }
+            }
+        }
+
+        println!("{}", "=".repeat(80));
+        if failed_tables > 0 {
+            bail!(
+                "Pre-flight thất bại ở {} bảng, chưa có thay đổi nào được ghi",
+                failed_tables
+            );
+        }
+        Ok(())
+    }
+
+    /// Tìm username trùng giữa các server và quyết định account nào bị đổi tên
+    /// theo `username_conflict`. Chỉ đọc dữ liệu, việc đổi tên thực hiện trong merge_accounts.
+    fn plan_username_renames(&mut self) -> Result<()> {
+        println!("\n{}", ">>> Kiểm tra trùng username...".bright_yellow());
+
+        // (id, username, tongnap, lần login cuối)
+        type AccountInfo = (i32, String, i64, i64);
+
+        let query = "SELECT `id`, `username`, COALESCE(`tongnap`, 0),
+                            COALESCE(UNIX_TIMESTAMP(`last_time_login`), 0)
+                     FROM account";
+        let mut target_conn = self.target_pool.get_conn()?;
+        let target_accounts: Vec<AccountInfo> = target_conn.query(query)?;
+        let mut source_accounts: Vec<Vec<AccountInfo>> = Vec::new();
+        for source in &self.sources {
+            // Account đã merge ở lần chạy trước nằm sẵn trong đích
+            if self.is_table_completed(source.config.server, "account") {
+                source_accounts.push(Vec::new());
+                continue;
+            }
+            source_accounts.push(source.pool.get_conn()?.query(query)?);
+        }
+
+        let max_len: Option<u64> = target_conn.query_first(
+            "SELECT CHARACTER_MAXIMUM_LENGTH FROM INFORMATION_SCHEMA.COLUMNS
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'account' AND COLUMN_NAME = 'username'",
         )?;
+        let max_len = max_len.map(|len| len as usize);
+
+        // Account đang giữ từng username: (index nguồn, None = đích) + thông tin account
+        let mut holders: HashMap<String, (Option<usize>, &AccountInfo)> = target_accounts
+            .iter()
+            .map(|account| (report::name_key(&account.1), (None, account)))
+            .collect();
+        // (server giữ tên, account giữ tên, server trùng, account trùng, username)
+        let mut conflicts: Vec<(u8, i32, u8, i32, String)> = Vec::new();
+        // Account thua phải đổi tên: (index nguồn, None = đích) + account
+        let mut losers: Vec<(Option<usize>, &AccountInfo)> = Vec::new();
+
+        let server_of = |index: Option<usize>| match index {
+            Some(i) => self.sources[i].config.server,
+            None => self.target.server,
+        };
+
+        for (index, accounts) in source_accounts.iter().enumerate() {
+            for account in accounts {
+                let key = report::name_key(&account.1);
+                let Some(&(holder_index, holder)) = holders.get(&key) else {
+                    holders.insert(key, (Some(index), account));
+                    continue;
+                };
+
+                conflicts.push((
+                    server_of(holder_index),
+                    holder.0,
+                    server_of(Some(index)),
+                    account.0,
+                    account.1.clone(),
+                ));
+
+                let holder_wins = match self.config.merge.username_conflict {
+                    UsernameConflictPolicy::KeepHigher => {
+                        (holder.2, holder.3) >= (account.2, account.3)
+                    }
+                    _ => true,
+                };
+                if holder_wins {
+                    losers.push((Some(index), account));
+                } else {
+                    losers.push((holder_index, holder));
+                    holders.insert(key, (Some(index), account));
+                }
+            }
+        }
 
-        if account_has_old_id.is_none() {
-            println!("  Tạo cột old_id cho bảng account...");
-            if !self.dry_run {
-                conn.query_drop("ALTER TABLE account ADD COLUMN old_id INT NULL COMMENT 'ID cũ trước khi merge'")?;
+        if conflicts.is_empty() {
+            println!("{} Không có username trùng", "✓".green());
+            return Ok(());
+        }
+
+        println!(
+            "{} {} username trùng giữa các server (policy: {:?})",
+            "⚠".yellow(),
+            conflicts.len(),
+            self.config.merge.username_conflict
+        );
+
+        if self.config.merge.username_conflict == UsernameConflictPolicy::Abort {
+            let path = Path::new(&self.config.merge.report_directory)
+                .join(&self.report_id)
+                .join("username_conflicts.csv");
+            fs::create_dir_all(path.parent().unwrap())?;
+            report::write_csv(
+                &path,
+                &[
+                    "username",
+                    "holder_server",
+                    "holder_account_id",
+                    "server",
+                    "account_id",
+                ],
+                conflicts.iter().map(|c| {
+                    vec![
+                        c.4.clone(),
+                        c.0.to_string(),
+                        c.1.to_string(),
+                        c.2.to_string(),
+                        c.3.to_string(),
+                    ]
+                }),
+            )?;
+            bail!(
+                "Có {} username trùng, đã ghi danh sách vào {}",
+                conflicts.len(),
+                path.display()
+            );
+        }
+
+        // Tên đã dùng ở tất cả server, tên mới sinh ra cũng được thêm vào để không trùng nhau
+        let mut taken: HashSet<String> = target_accounts
+            .iter()
+            .chain(source_accounts.iter().flatten())
+            .map(|account| report::name_key(&account.1))
+            .collect();
+
+        let mut renames: Vec<(Option<usize>, i32, String, String)> = Vec::new();
+        for (index, account) in losers {
+            let suffix = self
+                .config
+                .merge
+                .username_suffix
+                .replace("{server}", &server_of(index).to_string());
+            let new_name = report::unique_name(&account.1, &suffix, max_len, &taken);
+            taken.insert(report::name_key(&new_name));
+            renames.push((index, account.0, account.1.clone(), new_name));
+        }
+
+        for (index, id, old_username, new_username) in renames {
+            let (server, account_id) = match index {
+                Some(i) => {
+                    let source = &mut self.sources[i];
+                    source.username_renames.insert(id, new_username.clone());
+                    (source.config.server, id + source.id_offset)
+                }
+                None => {
+                    self.target_username_renames
+                        .push((id, new_username.clone()));
+                    (self.target.server, id)
+                }
+            };
+            self.report.account_renames.push(AccountRename {
+                server,
+                account_id,
+                old_username,
+                new_username,
+            });
+        }
+
+        println!(
+            "{} {} account sẽ được đổi username",
+            "✓".green(),
+            self.report.account_renames.len()
+        );
+        Ok(())
+    }
+
+    /// Tìm `name` của các nguồn trùng với đích hoặc với nguồn đứng trước và sinh tên mới.
+    /// Trả về danh sách (ID cũ, tên cũ, tên mới) cho từng nguồn theo thứ tự.
+    fn plan_name_renames(
+        &self,
+        table: &str,
+        rule: NameConflictRule,
+        suffix_template: &str,
+    ) -> Result<Vec<Vec<(i32, String, String)>>> {
+        let query = format!("SELECT `id`, `name` FROM `{}`", table);
+        let mut target_conn = self.target_pool.get_conn()?;
+        let target_rows: Vec<(i32, String)> = target_conn.query(&query)?;
+        let mut source_rows: Vec<Vec<(i32, String)>> = Vec::new();
+        for source in &self.sources {
+            if self.is_table_completed(source.config.server, table) {
+                source_rows.push(Vec::new());
+                continue;
             }
-            println!("{} Đã tạo cột old_id cho bảng account", "✓".green());
-        } else {
-            println!("{} Cột old_id đã tồn tại trong bảng account", "✓".green());
+            source_rows.push(source.pool.get_conn()?.query(&query)?);
         }
 
-        // Kiểm tra và thêm cột old_id cho bảng player
-        let player_has_old_id: Option<String> = conn.query_first(
-            "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
-             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'player' AND COLUMN_NAME = 'old_id'",
+        let max_len: Option<u64> = target_conn.exec_first(
+            "SELECT CHARACTER_MAXIMUM_LENGTH FROM INFORMATION_SCHEMA.COLUMNS
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND COLUMN_NAME = 'name'",
+            (table,),
         )?;
+        let max_len = max_len.map(|len| len as usize);
+
+        let mut taken: HashSet<String> = target_rows
+            .iter()
+            .chain(source_rows.iter().flatten())
+            .map(|(_, name)| report::name_key(name))
+            .collect();
+        // Tên đã thuộc về đích hoặc các nguồn đã xử lý (sau khi đổi tên)
+        let mut occupied: HashSet<String> = target_rows
+            .iter()
+            .map(|(_, name)| report::name_key(name))
+            .collect();
+
+        let mut result = Vec::new();
+        for (source, rows) in self.sources.iter().zip(&source_rows) {
+            let suffix = match rule {
+                NameConflictRule::NumericSuffix => String::new(),
+                _ => suffix_template.replace("{server}", &source.config.server.to_string()),
+            };
 
-        if player_has_old_id.is_none() {
-            println!("  Tạo cột old_id cho bảng player...");
-            if !self.dry_run {
-                conn.query_drop(
-                    "ALTER TABLE player ADD COLUMN old_id INT NULL COMMENT 'ID cũ trước khi merge'",
-                )?;
+            let mut renames = Vec::new();
+            for (id, name) in rows {
+                if !occupied.contains(&report::name_key(name)) {
+                    occupied.insert(report::name_key(name));
+                    continue;
+                }
+
+                let new_name = report::unique_name(name, &suffix, max_len, &taken);
+                taken.insert(report::name_key(&new_name));
+                occupied.insert(report::name_key(&new_name));
+                renames.push((*id, name.clone(), new_name));
             }
-            println!("{} Đã tạo cột old_id cho bảng player", "✓".green());
-        } else {
-            println!("{} Cột old_id đã tồn tại trong bảng player", "✓".green());
+            result.push(renames);
+        }
+        Ok(result)
+    }
+
+    /// Nhân vật của server nguồn trùng tên bị đổi tên theo `player_name_conflict`
+    fn plan_player_renames(&mut self) -> Result<()> {
+        println!("\n{}", ">>> Kiểm tra trùng tên nhân vật...".bright_yellow());
+
+        let rule = self.config.merge.player_name_conflict;
+        let renames =
+            self.plan_name_renames("player", rule, &self.config.merge.player_name_suffix)?;
+
+        let mut total = 0;
+        for (source, renames) in self.sources.iter_mut().zip(renames) {
+            for (id, old_name, new_name) in renames {
+                source.player_renames.insert(id, new_name.clone());
+                self.report.player_renames.push(PlayerRename {
+                    server: source.config.server,
+                    player_id: id + source.id_offset,
+                    old_name,
+                    new_name,
+                    force_rename: rule == NameConflictRule::ForceRename,
+                });
+                total += 1;
+            }
+        }
+
+        if total == 0 {
+            println!("{} Không có tên nhân vật trùng", "✓".green());
+        } else {
+            println!(
+                "{} {} nhân vật trùng tên sẽ được đổi tên (rule: {:?})",
+                "⚠".yellow(),
+                total,
+                rule
+            );
+        }
+        Ok(())
+    }
+
+    /// Clan của server nguồn trùng tên bị đổi tên theo `clan_name_conflict`
+    fn plan_clan_renames(&mut self) -> Result<()> {
+        println!("\n{}", ">>> Kiểm tra trùng tên clan...".bright_yellow());
+
+        let rule = self.config.merge.clan_name_conflict;
+        if rule == NameConflictRule::ForceRename {
+            bail!(
+                "clan_name_conflict không hỗ trợ force_rename (chỉ server_suffix / numeric_suffix)"
+            );
+        }
+
+        // Mỗi bảng clan_sv{n} được merge có tên clan riêng
+        let clan_tables: Vec<String> = self
+            .tables
+            .iter()
+            .filter(|t| t.is_clan())
+            .map(|t| t.name.clone())
+            .collect();
+        let mut total = 0;
+        for table_name in clan_tables {
+            let renames =
+                self.plan_name_renames(&table_name, rule, &self.config.merge.clan_name_suffix)?;
+            for (source, renames) in self.sources.iter_mut().zip(renames) {
+                for (id, old_name, new_name) in renames {
+                    source
+                        .clan_renames
+                        .entry(table_name.clone())
+                        .or_default()
+                        .insert(id, new_name.clone());
+                    self.report.clan_renames.push(ClanRename {
+                        server: source.config.server,
+                        table: table_name.clone(),
+                        clan_id: id + source.id_offset,
+                        old_name,
+                        new_name,
+                    });
+                    total += 1;
+                }
+            }
+        }
+
+        if total == 0 {
+            println!("{} Không có tên clan trùng", "✓".green());
+        } else {
+            println!(
+                "{} {} clan trùng tên sẽ được đổi tên (rule: {:?})",
+                "⚠".yellow(),
+                total,
+                rule
+            );
+        }
+        Ok(())
+    }
+
+    /// Ghi lại các cột bị đặt lại theo `reset_columns` (vd: `clan_id_sv{n}` khi
+    /// `other_clan_columns = "reset"`) cùng số row nguồn bị mất tham chiếu
+    fn plan_column_resets(&mut self) -> Result<()> {
+        let resets: Vec<(String, String, i64)> = self
+            .tables
+            .iter()
+            .flat_map(|t| {
+                t.reset_columns
+                    .iter()
+                    .map(|(column, value)| (t.name.clone(), column.clone(), *value))
+            })
+            .collect();
+        if resets.is_empty() {
+            return Ok(());
+        }
+
+        println!("\n{}", ">>> Kiểm tra cột bị đặt lại...".bright_yellow());
+        for source in &self.sources {
+            let mut conn = source.pool.get_conn()?;
+            for (table, column, value) in &resets {
+                if self.is_table_completed(source.config.server, table) {
+                    continue;
+                }
+                let exists: Option<String> = conn.exec_first(
+                    "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
+                     WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND COLUMN_NAME = ?",
+                    (table, column),
+                )?;
+                if exists.is_none() {
+                    continue;
+                }
+                let rows: Option<u64> = conn.exec_first(
+                    format!(
+                        "SELECT COUNT(*) FROM `{}` WHERE `{}` IS NULL OR `{}` <> ?",
+                        table, column, column
+                    ),
+                    (value,),
+                )?;
+                let rows = rows.unwrap_or(0);
+                println!(
+                    "{} Server {}: {}.{} = {} ({} row mất tham chiếu)",
+                    "⚠".yellow(),
+                    source.config.server,
+                    table,
+                    column,
+                    value,
+                    rows
+                );
+                self.report.column_resets.push(ColumnReset {
+                    server: source.config.server,
+                    table: table.clone(),
+                    column: column.clone(),
+                    value: *value,
+                    rows,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn write_report(&self) -> Result<()> {
+        let dir = self
+            .report
+            .write(&self.config.merge.report_directory, &self.report_id)?;
+        println!("{} Report: {}", "✓".green(), dir.display());
+        Ok(())
+    }
+
+    fn format_examples(ids: &[i64], offset: i64) -> String {
+        let mut examples: Vec<String> = ids
+            .iter()
+            .take(5)
+            .map(|id| format!("{} -> {}", id, id + offset))
+            .collect();
+        if ids.len() > 5 {
+            examples.push("...".to_string());
+        }
+        examples.join(", ")
+    }
+
+    /// Sau khi commit, đảm bảo AUTO_INCREMENT của các bảng bị remap lớn hơn MAX(id) mới
+    /// để ID sinh ra sau merge không đụng vào dải ID vừa chèn.
+    fn ensure_auto_increments(&self, conn: &mut PooledConn) -> Result<()> {
+        println!("\n{}", ">>> Kiểm tra AUTO_INCREMENT...".bright_yellow());
+
+        // MySQL 8 cache AUTO_INCREMENT trong INFORMATION_SCHEMA, tắt cache cho session này
+        // (MariaDB / MySQL 5.7 không có biến này nên bỏ qua lỗi)
+        let _ = conn.query_drop("SET SESSION information_schema_stats_expiry = 0");
+
+        for (table, pk, _) in self.remapped_tables() {
+            let max_id: Option<i64> =
+                conn.query_first(format!("SELECT MAX(`{}`) FROM `{}`", pk, table))?;
+            let auto_increment = Self::auto_increment(conn, &table)?;
+
+            let (Some(max_id), Some(auto_increment)) = (max_id, auto_increment) else {
+                continue;
+            };
+
+            if auto_increment > max_id {
+                println!(
+                    "{} {:<25} AUTO_INCREMENT = {} (MAX(id) = {})",
+                    "✓".green(),
+                    table,
+                    auto_increment,
+                    max_id
+                );
+            } else {
+                conn.query_drop(format!(
+                    "ALTER TABLE `{}` AUTO_INCREMENT = {}",
+                    table,
+                    max_id + 1
+                ))?;
+                println!(
+                    "{} {:<25} AUTO_INCREMENT {} -> {}",
+                    "✓".green(),
+                    table,
+                    auto_increment,
+                    max_id + 1
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// AUTO_INCREMENT hiện tại của bảng, None nếu bảng không có cột AUTO_INCREMENT
+    fn auto_increment(conn: &mut PooledConn, table: &str) -> Result<Option<i64>> {
+        let auto_increment: Option<Option<i64>> = conn.exec_first(
+            "SELECT AUTO_INCREMENT FROM INFORMATION_SCHEMA.TABLES
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+            (table,),
+        )?;
+        Ok(auto_increment.flatten())
+    }
+
+    /// AUTO_INCREMENT của các bảng merge trước transaction. InnoDB không rollback bộ đếm
+    /// nên sau ROLLBACK phải đặt lại, nếu không lần merge thật sẽ lệch offset / dải ID.
+    fn save_auto_increments(&self, conn: &mut PooledConn) -> Result<Vec<(String, i64)>> {
+        // MySQL 8 cache AUTO_INCREMENT trong INFORMATION_SCHEMA (xem ensure_auto_increments)
+        let _ = conn.query_drop("SET SESSION information_schema_stats_expiry = 0");
+
+        let mut saved = Vec::new();
+        for table in self.merged_tables() {
+            if !Self::table_exists(conn, &table)? {
+                continue;
+            }
+            if let Some(auto_increment) = Self::auto_increment(conn, &table)? {
+                saved.push((table, auto_increment));
+            }
+        }
+        Ok(saved)
+    }
+
+    /// ROLLBACK transaction merge rồi đặt lại AUTO_INCREMENT đã lưu.
+    /// Bảng không đặt lại được (vd: thiếu quyền ALTER) được cảnh báo theo tên,
+    /// trả về false nếu có bảng như vậy.
+    fn rollback_merge(&self, conn: &mut PooledConn, saved: &[(String, i64)]) -> Result<bool> {
+        conn.query_drop("ROLLBACK")?;
+
+        let mut moved = Vec::new();
+        for (table, auto_increment) in saved {
+            let restored = conn
+                .query_drop(format!(
+                    "ALTER TABLE `{}` AUTO_INCREMENT = {}",
+                    table, auto_increment
+                ))
+                .map_err(anyhow::Error::from)
+                .and_then(|_| Self::auto_increment(conn, table));
+            match restored {
+                Ok(Some(current)) if current == *auto_increment => {}
+                Ok(current) => moved.push(format!(
+                    "{} ({} -> {})",
+                    table,
+                    auto_increment,
+                    current.map_or("-".to_string(), |v| v.to_string())
+                )),
+                Err(e) => moved.push(format!("{} ({})", table, e)),
+            }
+        }
+        if !moved.is_empty() {
+            println!(
+                "{} AUTO_INCREMENT không đặt lại được sau ROLLBACK: {}",
+                "⚠".yellow(),
+                moved.join(", ")
+            );
+        }
+        Ok(moved.is_empty())
+    }
+
+    /// Các bảng bị merge ghi vào, cũng là danh sách bảng cần backup
+    fn merged_tables(&self) -> Vec<String> {
+        self.tables.iter().map(|table| table.name.clone()).collect()
+    }
+
+    fn run_backup(&self) -> Result<()> {
+        println!(
+            "\n{}",
+            ">>> Backup dữ liệu trước khi merge...".bright_yellow()
+        );
+
+        let backup_dir =
+            backup::create_backup_dir(&self.config.merge.backup_directory, &self.run_id)?;
+        let tables = self.merged_tables();
+
+        let mut servers = vec![backup::backup_server(
+            &self.target_pool,
+            self.target.server,
+            &self.target.db,
+            &tables,
+            &backup_dir,
+        )?];
+        for source in &self.sources {
+            servers.push(backup::backup_server(
+                &source.pool,
+                source.config.server,
+                &source.config.db,
+                &tables,
+                &backup_dir,
+            )?);
+        }
+
+        let manifest = backup::BackupManifest {
+            created_at: chrono::Local::now().to_rfc3339(),
+            servers,
+        };
+        backup::write_manifest(&backup_dir, &manifest)?;
+
+        println!(
+            "{} Backup hoàn thành: {}",
+            "✓".green(),
+            backup_dir.display()
+        );
+        Ok(())
+    }
+
+    fn run_merge(&mut self, target_conn: &mut PooledConn) -> Result<()> {
+        // Tắt foreign key check tạm thời
+        self.write_sql(target_conn, "SET FOREIGN_KEY_CHECKS=0", ())?;
+
+        // Đổi tên account sẵn có ở đích trước để nhường username cho account nguồn
+        let target_server = self.target.server;
+        self.run_steps(
+            target_conn,
+            target_server,
+            0,
+            &[MergeStep::TargetRenames],
+            |conn, _| {
+                if !self.dry_run {
+                    for (id, new_name) in &self.target_username_renames {
+                        self.write_sql(
+                            conn,
+                            "UPDATE account SET `username` = ? WHERE `id` = ?",
+                            (new_name, id),
+                        )?;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+
+        // Merge lần lượt từng server nguồn theo thứ tự trong config
+        let mut sources = std::mem::take(&mut self.sources);
+        let result = self.merge_sources(target_conn, &mut sources);
+        self.sources = sources;
+        result?;
+
+        // Bật lại foreign key check
+        self.write_sql(target_conn, "SET FOREIGN_KEY_CHECKS=1", ())?;
+
+        // Verify (--emit-sql thì dữ liệu chưa được ghi nên không verify được)
+        if self.script.is_some() {
+            self.script_comment(
+                "Verify sau khi apply: SELECT COUNT(*) FROM player p LEFT JOIN account a ON p.account_id = a.id WHERE a.id IS NULL",
+            )?;
+        } else {
+            self.verify_merge(target_conn)?;
+        }
+
+        self.collect_report();
+        Ok(())
+    }
+
+    /// Chuyển mapping, timing và số liệu từng nguồn vào report. Gọi lại được (vd: ghi
+    /// report khi lỗi sau khi đã ghi report lúc commit), timing đã chuyển không bị mất.
+    fn collect_report(&mut self) {
+        self.report.id_mappings = self.collect_id_mappings();
+        let timings = std::mem::take(self.insert_timings.get_mut().unwrap());
+        self.report.insert_timings.extend(timings);
+        let unmapped = std::mem::take(self.unmapped_json_ids.get_mut().unwrap());
+        self.report.unmapped_json_ids.extend(unmapped);
+
+        self.report.sources = self
+            .sources
+            .iter()
+            .map(|source| SourceSummary {
+                server: source.config.server,
+                database: source.config.db.describe(),
+                id_offset: source.id_offset,
+                accounts: source.mapped_count("account"),
+                players: source.mapped_count("player"),
+                clans: self
+                    .tables
+                    .iter()
+                    .filter(|t| t.is_clan())
+                    .filter_map(|t| t.entity.as_deref())
+                    .map(|entity| source.mapped_count(entity))
+                    .sum(),
+            })
+            .collect();
+    }
+
+    fn merge_sources(
+        &self,
+        target_conn: &mut PooledConn,
+        sources: &mut [SourceServer],
+    ) -> Result<()> {
+        for source in sources {
+            println!(
+                "\n{}",
+                format!(
+                    "=== MERGE SERVER {} ({}) ===",
+                    source.config.server,
+                    source.config.db.describe()
+                )
+                .bright_cyan()
+            );
+
+            // Chia các bảng thành từng đợt theo phụ thuộc, mỗi đợt tối đa `workers` bảng.
+            // workers = 1 thì chạy lần lượt theo `order` của registry.
+            let workers = self.workers();
+            let mut pending: Vec<usize> = (0..self.tables.len()).collect();
+            let mut done: Vec<usize> = Vec::new();
+            while !pending.is_empty() {
+                let wave: Vec<MergeStep> = pending
+                    .iter()
+                    .copied()
+                    .filter(|&i| self.dependencies(i).iter().all(|dep| done.contains(dep)))
+                    .take(workers)
+                    .map(MergeStep::Table)
+                    .collect();
+                if wave.is_empty() {
+                    bail!(
+                        "Registry bảng có phụ thuộc vòng: {}",
+                        pending
+                            .iter()
+                            .map(|&i| self.tables[i].name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+                pending.retain(|&i| !wave.contains(&MergeStep::Table(i)));
+
+                let mappings = self
+                    .merge_wave(target_conn, source, &wave)
+                    .with_context(|| format!("Merge Server {} thất bại", source.config.server))?;
+                source.mappings.extend(mappings);
+                done.extend(wave.iter().filter_map(|step| match step {
+                    MergeStep::Table(i) => Some(*i),
+                    MergeStep::TargetRenames => None,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bảng phải merge xong trước bảng `index`: bảng entity mà bảng này tra mapping
+    /// (tham chiếu của bảng `rows` / `load_data` và ID trong cột JSON).
+    /// Bảng `temp_table` remap tham chiếu bằng cộng offset nên không phải chờ.
+    fn dependencies(&self, index: usize) -> Vec<usize> {
+        let table = &self.tables[index];
+        let mut entities: Vec<&str> = table.json_references.values().map(String::as_str).collect();
+        if table.strategy != CopyStrategy::TempTable {
+            entities.extend(table.references.values().map(String::as_str));
+        }
+        self.tables
+            .iter()
+            .enumerate()
+            .filter(|(i, t)| {
+                *i != index
+                    && t.entity
+                        .as_deref()
+                        .is_some_and(|entity| entities.contains(&entity))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Số bước chạy song song (--emit-sql luôn tuần tự để file SQL giữ đúng thứ tự)
+    fn workers(&self) -> usize {
+        if self.emit_sql.is_some() {
+            1
+        } else {
+            self.config.merge.workers.max(1)
+        }
+    }
+
+    /// Chạy 1 đợt bước merge của 1 server nguồn, trả về mapping ID mới tạo ra theo entity.
+    /// Mapping ID được lưu cùng transaction với bước tạo ra nó.
+    fn merge_wave(
+        &self,
+        target_conn: &mut PooledConn,
+        source: &SourceServer,
+        steps: &[MergeStep],
+    ) -> Result<Vec<(String, HashMap<i32, i32>)>> {
+        let mut mappings = Vec::new();
+        self.run_steps(
+            target_conn,
+            source.config.server,
+            source.id_offset,
+            steps,
+            |conn, steps| {
+                mappings = match steps {
+                    [step] => {
+                        let mut source_conn = source.pool.get_conn()?;
+                        self.merge_step(conn, &mut source_conn, source, *step)?
+                            .into_iter()
+                            .collect()
+                    }
+                    steps => self.merge_parallel(conn, source, steps)?,
+                };
+                Ok(())
+            },
+        )?;
+        Ok(mappings)
+    }
+
+    /// Merge 1 bảng của server nguồn, bảng entity trả về (entity, mapping ID)
+    fn merge_step(
+        &self,
+        target_conn: &mut PooledConn,
+        source_conn: &mut PooledConn,
+        source: &SourceServer,
+        step: MergeStep,
+    ) -> Result<Option<(String, HashMap<i32, i32>)>> {
+        let MergeStep::Table(i) = step else {
+            return Ok(None);
+        };
+        let table = &self.tables[i];
+        let mapping = self.merge_table(target_conn, source_conn, source, table)?;
+        match (&table.entity, mapping) {
+            (Some(entity), Some(mapping)) => {
+                self.save_id_mappings(target_conn, source.config.server, entity, &mapping)?;
+                Ok(Some((entity.clone(), mapping)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Chạy song song nhiều bước độc lập. Mỗi worker đọc nguồn và đích (schema) trên
+    /// kết nối riêng, còn câu ghi được đẩy qua hàng đợi và chạy lần lượt trên
+    /// `target_conn`, nên toàn bộ vẫn nằm trong 1 transaction (commit / rollback 1 lần).
+    /// Driver mysql là blocking nên worker là thread thường.
+    fn merge_parallel(
+        &self,
+        target_conn: &mut PooledConn,
+        source: &SourceServer,
+        steps: &[MergeStep],
+    ) -> Result<Vec<(String, HashMap<i32, i32>)>> {
+        println!(
+            "\n{} Chạy song song: {}",
+            "⇉".bright_cyan(),
+            steps
+                .iter()
+                .map(|&step| self.step_name(step))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        // Hàng đợi có giới hạn để worker không đọc nguồn nhanh hơn tốc độ ghi quá nhiều
+        let (sender, receiver) = mpsc::sync_channel::<QueuedWrite>(steps.len() * 2);
+
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = steps
+                .iter()
+                .map(|&step| {
+                    let sender = sender.clone();
+                    scope.spawn(move || {
+                        WRITE_QUEUE.with(|queue| *queue.borrow_mut() = Some(sender));
+                        let result = (|| {
+                            let mut read_conn = self.target_pool.get_conn()?;
+                            let mut source_conn = source.pool.get_conn()?;
+                            // Worker chờ hàng đợi đầy thì ngừng đọc stream nguồn, server nguồn
+                            // sẽ cắt kết nối sau net_write_timeout (mặc định 60s) nếu không nới
+                            source_conn.query_drop(format!(
+                                "SET SESSION net_write_timeout = {}",
+                                WORKER_NET_WRITE_TIMEOUT_SECS
+                            ))?;
+                            self.merge_step(&mut read_conn, &mut source_conn, source, step)
+                        })();
+                        // Đóng hàng đợi của worker để thread ghi biết đã hết câu lệnh
+                        WRITE_QUEUE.with(|queue| queue.borrow_mut().take());
+                        result.with_context(|| format!("Bước {} thất bại", self.step_name(step)))
+                    })
+                })
+                .collect();
+            drop(sender);
+
+            // Thread hiện tại là thread ghi duy nhất trên kết nối đích
+            let mut write_result = Ok(());
+            for write in receiver.iter() {
+                let result = match write {
+                    QueuedWrite::Sql(sql, params, timer) => {
+                        self.write_timed(target_conn, sql, params, timer.as_ref())
+                    }
+                    QueuedWrite::Load(file) => self.load_bulk_file(target_conn, file),
+                    QueuedWrite::Timing(server, batch) => self.record_batch_timing(server, batch),
+                };
+                if let Err(e) = result {
+                    write_result = Err(e);
+                    break;
+                }
+            }
+            // Ghi lỗi thì đóng hàng đợi, worker đang gửi sẽ nhận lỗi và dừng
+            drop(receiver);
+
+            let mut mappings = Vec::new();
+            let mut worker_error = None;
+            for (handle, &step) in handles.into_iter().zip(steps) {
+                match handle.join() {
+                    Ok(Ok(mapping)) => mappings.extend(mapping),
+                    Ok(Err(e)) => {
+                        worker_error.get_or_insert(e);
+                    }
+                    Err(_) => {
+                        worker_error
+                            .get_or_insert(anyhow!("Bước {} bị panic", self.step_name(step)));
+                    }
+                }
+            }
+
+            write_result?;
+            match worker_error {
+                Some(e) => Err(e),
+                None => Ok(mappings),
+            }
+        })
+    }
+
+    fn is_completed(&self, server: u8, step: MergeStep) -> bool {
+        self.is_table_completed(server, self.step_name(step))
+    }
+
+    fn is_table_completed(&self, server: u8, table: &str) -> bool {
+        self.completed_steps.contains(&(server, table.to_string()))
+    }
+
+    /// Tên bước trong checkpoint: `target_renames` hoặc tên bảng
+    fn step_name(&self, step: MergeStep) -> &str {
+        match step {
+            MergeStep::TargetRenames => "target_renames",
+            MergeStep::Table(i) => &self.tables[i].name,
+        }
+    }
+
+    /// Chạy 1 đợt bước merge. Chế độ resumable thì cả đợt chạy trong transaction riêng
+    /// và ghi checkpoint từng bước trước khi commit, bước đã có checkpoint thì bỏ qua.
+    fn run_steps(
+        &self,
+        conn: &mut PooledConn,
+        server: u8,
+        id_offset: i32,
+        steps: &[MergeStep],
+        run: impl FnOnce(&mut PooledConn, &[MergeStep]) -> Result<()>,
+    ) -> Result<()> {
+        let mut pending = Vec::new();
+        for &step in steps {
+            if self.is_completed(server, step) {
+                println!(
+                    "\n{} Bỏ qua {} của Server {} (đã commit ở lần chạy trước)",
+                    "-".dimmed(),
+                    self.step_name(step),
+                    server
+                );
+            } else {
+                pending.push(step);
+            }
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        if !self.resumable || self.dry_run {
+            return run(conn, &pending);
+        }
+
+        conn.query_drop("START TRANSACTION")?;
+        let result = run(conn, &pending).and_then(|_| {
+            for step in &pending {
+                let step_name = self.step_name(*step);
+                conn.exec_drop(
+                    format!(
+                        "INSERT INTO `{}` (`run_id`, `source_server`, `step`, `id_offset`) VALUES (?, ?, ?, ?)",
+                        CHECKPOINT_TABLE
+                    ),
+                    (&self.run_id, server, step_name, id_offset),
+                )?;
+                conn.exec_batch(
+                    format!(
+                        "INSERT INTO `{}` (`run_id`, `source_server`, `step`, `kind`, `entity_id`,
+                                           `old_name`, `new_name`, `force_rename`)
+                         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                        RENAME_TABLE
+                    ),
+                    self.step_renames(server, step_name).into_iter().map(
+                        |(kind, entity_id, old_name, new_name, force_rename)| {
+                            (
+                                &self.run_id,
+                                server,
+                                step_name,
+                                kind,
+                                entity_id,
+                                old_name,
+                                new_name,
+                                force_rename,
+                            )
+                        },
+                    ),
+                )?;
+            }
+            Self::ensure_transaction_open(conn)?;
+            conn.query_drop("COMMIT")?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            conn.query_drop("ROLLBACK")?;
+            return Err(e);
+        }
+        for step in &pending {
+            println!(
+                "{} Checkpoint: Server {} / {}",
+                "✓".green(),
+                server,
+                self.step_name(*step)
+            );
+        }
+        Ok(())
+    }
+
+    /// Tên bị đổi bởi 1 bước merge, ghi cùng checkpoint của bước:
+    /// (loại, ID ở đích, tên cũ, tên mới, force_rename)
+    fn step_renames(&self, server: u8, step: &str) -> Vec<(&'static str, i32, &str, &str, bool)> {
+        let entity_table = |entity: &str| {
+            self.tables
+                .iter()
+                .find(|t| t.entity.as_deref() == Some(entity))
+                .map(|t| t.name.as_str())
+        };
+        // Account của đích được đổi tên ở bước target_renames, của nguồn ở bước account
+        let account_step = if server == self.target.server {
+            Some("target_renames")
+        } else {
+            entity_table("account")
+        };
+
+        let mut renames = Vec::new();
+        if account_step == Some(step) {
+            renames.extend(
+                self.report
+                    .account_renames
+                    .iter()
+                    .filter(|r| r.server == server)
+                    .map(|r| {
+                        let (old, new) = (r.old_username.as_str(), r.new_username.as_str());
+                        ("account", r.account_id, old, new, false)
+                    }),
+            );
+        }
+        if entity_table("player") == Some(step) {
+            renames.extend(
+                self.report
+                    .player_renames
+                    .iter()
+                    .filter(|r| r.server == server)
+                    .map(|r| {
+                        let (old, new) = (r.old_name.as_str(), r.new_name.as_str());
+                        ("player", r.player_id, old, new, r.force_rename)
+                    }),
+            );
+        }
+        renames.extend(
+            self.report
+                .clan_renames
+                .iter()
+                .filter(|r| r.server == server && r.table == step)
+                .map(|r| {
+                    (
+                        "clan",
+                        r.clan_id,
+                        r.old_name.as_str(),
+                        r.new_name.as_str(),
+                        false,
+                    )
+                }),
+        );
+        renames
+    }
+
+    /// Đọc các bước đã commit, ID offset và mapping ID của lần chạy `run_id`
+    fn load_checkpoints(&mut self) -> Result<()> {
+        println!(
+            "\n{}",
+            format!(">>> Đọc checkpoint của lần chạy {}...", self.run_id).bright_yellow()
+        );
+
+        let mut conn = self.target_pool.get_conn()?;
+        let exists: Option<String> = conn.exec_first(
+            "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+            (CHECKPOINT_TABLE,),
+        )?;
+        if exists.is_none() {
+            bail!(
+                "Không có bảng {} ở database đích, không thể resume",
+                CHECKPOINT_TABLE
+            );
+        }
+
+        let checkpoints: Vec<(u8, String, i32)> = conn.exec(
+            format!(
+                "SELECT `source_server`, `step`, `id_offset` FROM `{}` WHERE `run_id` = ?",
+                CHECKPOINT_TABLE
+            ),
+            (&self.run_id,),
+        )?;
+        if checkpoints.is_empty() {
+            bail!("Không tìm thấy checkpoint nào của lần chạy {}", self.run_id);
+        }
+
+        for (server, step, id_offset) in checkpoints {
+            if step != "target_renames" {
+                if !self.tables.iter().any(|table| table.name == step) {
+                    bail!(
+                        "Checkpoint không hợp lệ: bước {} không có trong registry bảng",
+                        step
+                    );
+                }
+                self.resumed_offsets.insert(server, id_offset);
+            }
+            println!("{} Server {} / {} đã commit", "✓".green(), server, step);
+            self.completed_steps.insert((server, step));
+        }
+
+        // Bước đã commit không được lập kế hoạch đổi tên lại, lấy tên đã đổi từ lần chạy trước
+        let renames: Vec<(u8, String, String, i32, String, String, bool)> =
+            if Self::table_exists(&mut conn, RENAME_TABLE)? {
+                conn.exec(
+                    format!(
+                    "SELECT `source_server`, `step`, `kind`, `entity_id`, `old_name`, `new_name`,
+                                `force_rename`
+                         FROM `{}` WHERE `run_id` = ? ORDER BY `id`",
+                    RENAME_TABLE
+                ),
+                    (&self.run_id,),
+                )?
+            } else {
+                Vec::new()
+            };
+        for (server, step, kind, id, old_name, new_name, force_rename) in renames {
+            match kind.as_str() {
+                "account" => self.report.account_renames.push(AccountRename {
+                    server,
+                    account_id: id,
+                    old_username: old_name,
+                    new_username: new_name,
+                }),
+                "player" => self.report.player_renames.push(PlayerRename {
+                    server,
+                    player_id: id,
+                    old_name,
+                    new_name,
+                    force_rename,
+                }),
+                "clan" => self.report.clan_renames.push(ClanRename {
+                    server,
+                    table: step,
+                    clan_id: id,
+                    old_name,
+                    new_name,
+                }),
+                _ => bail!("{} có loại đổi tên không hợp lệ: {}", RENAME_TABLE, kind),
+            }
+        }
+
+        for source in &mut self.sources {
+            let server = source.config.server;
+            for table in &self.tables {
+                let Some(entity) = &table.entity else {
+                    continue;
+                };
+                if !self.completed_steps.contains(&(server, table.name.clone())) {
+                    continue;
+                }
+                let mapping: Vec<(i32, i32)> = conn.exec(
+                    format!(
+                        "SELECT `old_id`, `new_id` FROM `{}`
+                         WHERE `run_id` = ? AND `source_server` = ? AND `entity` = ?",
+                        ID_MAP_TABLE
+                    ),
+                    (&self.run_id, server, entity),
+                )?;
+                source
+                    .mappings
+                    .entry(entity.clone())
+                    .or_default()
+                    .extend(mapping);
+            }
+        }
+        Ok(())
+    }
+
+    /// Các thay đổi schema cần làm ở đích trước khi merge: (mô tả, câu lệnh).
+    /// ALTER / CREATE TABLE gây commit ngầm trong MySQL nên không được chạy trong transaction merge.
+    fn pending_schema_changes(&self, conn: &mut PooledConn) -> Result<Vec<(String, String)>> {
+        let mut changes = Vec::new();
+
+        let column_exists = |conn: &mut PooledConn, table: &str, column: &str| -> Result<bool> {
+            let found: Option<String> = conn.exec_first(
+                "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
+                 WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND COLUMN_NAME = ?",
+                (table, column),
+            )?;
+            Ok(found.is_some())
+        };
+        let table_exists = |conn: &mut PooledConn, table: &str| -> Result<bool> {
+            let found: Option<String> = conn.exec_first(
+                "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES
+                 WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+                (table,),
+            )?;
+            Ok(found.is_some())
+        };
+
+        // Cột chỉ có ở nguồn (server nguồn chạy bản build mới hơn), theo định nghĩa của
+        // nguồn đầu tiên có cột đó
+        if self.config.merge.align_schema {
+            let tool_columns = self.config.tool_columns(&self.tables);
+            let mut added = HashSet::new();
+            for source in &self.sources {
+                let additions = schema::missing_columns(
+                    &mut source.pool.get_conn()?,
+                    conn,
+                    &self.tables,
+                    &tool_columns,
+                )?;
+                for addition in additions {
+                    if added.insert((addition.table.clone(), addition.column.clone())) {
+                        changes.push((
+                            format!(
+                                "Thêm cột {} (từ server {}) cho bảng {}",
+                                addition.column, source.config.server, addition.table
+                            ),
+                            addition.statement,
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Cột lưu ID cũ cho các bảng entity (mặc định: old_id của account và player)
+        for table in &self.tables {
+            let Some(column) = &table.old_id_column else {
+                continue;
+            };
+            if !column_exists(conn, &table.name, column)? {
+                changes.push((
+                    format!("Tạo cột {} cho bảng {}", column, table.name),
+                    format!(
+                        "ALTER TABLE `{}` ADD COLUMN `{}` INT NULL COMMENT 'ID cũ trước khi merge'",
+                        table.name, column
+                    ),
+                ));
+            }
+        }
+
+        // Cột đánh dấu bắt đổi tên khi login (rule force_rename)
+        let column = &self.config.merge.force_rename_column;
+        if self.config.merge.player_name_conflict == NameConflictRule::ForceRename
+            && !column_exists(conn, "player", column)?
+        {
+            changes.push((
+                format!("Tạo cột {} cho bảng player", column),
+                format!(
+                    "ALTER TABLE player ADD COLUMN `{}` TINYINT(1) NOT NULL DEFAULT 0 COMMENT 'Bắt đổi tên khi login sau merge'",
+                    column
+                ),
+            ));
+        }
+
+        if !table_exists(conn, ID_MAP_TABLE)? {
+            changes.push((
+                format!("Tạo bảng {}", ID_MAP_TABLE),
+                format!(
+                    "CREATE TABLE `{}` (
+                        `id` BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+                        `run_id` VARCHAR(32) NOT NULL COMMENT 'Lần merge',
+                        `source_server` TINYINT UNSIGNED NOT NULL,
+                        `entity` VARCHAR(16) NOT NULL COMMENT 'account / player / clan',
+                        `old_id` INT NOT NULL,
+                        `new_id` INT NOT NULL,
+                        `created_at` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                        UNIQUE KEY `uk_old` (`run_id`, `source_server`, `entity`, `old_id`),
+                        KEY `idx_new` (`entity`, `new_id`)
+                    ) COMMENT 'Mapping ID cũ -> ID mới sau merge'",
+                    ID_MAP_TABLE
+                ),
+            ));
+        }
+
+        // Tên bước là tên bảng nên cột step phải chứa được 64 ký tự
+        if self.resumable && !table_exists(conn, CHECKPOINT_TABLE)? {
+            changes.push((
+                format!("Tạo bảng {}", CHECKPOINT_TABLE),
+                format!(
+                    "CREATE TABLE `{}` (
+                        `run_id` VARCHAR(32) NOT NULL,
+                        `source_server` TINYINT UNSIGNED NOT NULL,
+                        `step` VARCHAR(64) NOT NULL,
+                        `id_offset` INT NOT NULL,
+                        `completed_at` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                        PRIMARY KEY (`run_id`, `source_server`, `step`)
+                    ) COMMENT 'Các bước merge đã commit'",
+                    CHECKPOINT_TABLE
+                ),
+            ));
+        } else if self.resumable {
+            // Bảng checkpoint tạo bởi bản cũ có step VARCHAR(32)
+            let step_length: Option<Option<u64>> = conn.exec_first(
+                "SELECT CHARACTER_MAXIMUM_LENGTH FROM INFORMATION_SCHEMA.COLUMNS
+                 WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND COLUMN_NAME = 'step'",
+                (CHECKPOINT_TABLE,),
+            )?;
+            if matches!(step_length, Some(Some(length)) if length < 64) {
+                changes.push((
+                    format!(
+                        "Mở rộng cột step của bảng {} lên 64 ký tự",
+                        CHECKPOINT_TABLE
+                    ),
+                    format!(
+                        "ALTER TABLE `{}` MODIFY `step` VARCHAR(64) NOT NULL",
+                        CHECKPOINT_TABLE
+                    ),
+                ));
+            }
+        }
+
+        if self.resumable && !table_exists(conn, RENAME_TABLE)? {
+            changes.push((
+                format!("Tạo bảng {}", RENAME_TABLE),
+                format!(
+                    "CREATE TABLE `{}` (
+                        `id` BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+                        `run_id` VARCHAR(32) NOT NULL,
+                        `source_server` TINYINT UNSIGNED NOT NULL,
+                        `step` VARCHAR(64) NOT NULL,
+                        `kind` VARCHAR(16) NOT NULL COMMENT 'account / player / clan',
+                        `entity_id` INT NOT NULL COMMENT 'ID ở đích',
+                        `old_name` VARCHAR(255) NOT NULL,
+                        `new_name` VARCHAR(255) NOT NULL,
+                        `force_rename` TINYINT(1) NOT NULL DEFAULT 0,
+                        KEY `idx_run` (`run_id`, `source_server`, `step`)
+                    ) DEFAULT CHARSET = utf8mb4 COMMENT 'Tên đã đổi của các bước merge đã commit'",
+                    RENAME_TABLE
+                ),
+            ));
+        }
+
+        Ok(changes)
+    }
+
+    /// Giai đoạn chuẩn bị schema, chạy và xác nhận riêng trước transaction merge.
+    /// Trả về false nếu user không đồng ý thay đổi schema.
+    fn prepare_schema(&self, conn: &mut PooledConn) -> Result<bool> {
+        println!("\n{}", "=== CHUẨN BỊ SCHEMA ===".bright_cyan());
+
+        let changes = self.pending_schema_changes(conn)?;
+        if changes.is_empty() {
+            println!("{} Schema đích đã sẵn sàng", "✓".green());
+            return Ok(true);
+        }
+
+        println!(
+            "{} Các thay đổi sau được commit ngay, KHÔNG rollback được cùng dữ liệu merge:",
+            "⚠".yellow()
+        );
+        for (description, _) in &changes {
+            println!("  - {}", description);
+        }
+
+        if self.dry_run {
+            println!("{} DRY RUN: không thay đổi schema", "-".dimmed());
+            return Ok(true);
+        }
+
+        // DDL commit ngầm, dry-run full không được để lại thay đổi nào trên đích
+        if self.rollback_only {
+            bail!(
+                "--dry-run=full không thay đổi schema đích, cần chạy merge thật (hoặc áp dụng các thay đổi trên) trước"
+            );
+        }
+
+        if self.script.is_some() {
+            for (description, statement) in &changes {
+                self.script_comment(description)?;
+                self.write_sql(conn, statement, ())?;
+            }
+            return Ok(true);
+        }
+
+        println!("\n{} Thực hiện thay đổi schema? (yes/no): ", "⚠".yellow());
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if input.trim().to_lowercase() != "yes" {
+            return Ok(false);
+        }
+
+        for (description, statement) in &changes {
+            conn.query_drop(statement)
+                .with_context(|| format!("{} thất bại", description))?;
+            println!("{} {}", "✓".green(), description);
+        }
+
+        // Không còn thay đổi nào thì transaction merge chắc chắn không bị commit ngầm
+        let remaining = self.pending_schema_changes(conn)?;
+        if !remaining.is_empty() {
+            bail!(
+                "Schema vẫn chưa sẵn sàng sau khi chuẩn bị: {}",
+                remaining
+                    .iter()
+                    .map(|(description, _)| description.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(true)
+    }
+
+    /// Bảng dùng strategy `temp_table` và temp table tương ứng
+    fn temp_tables(&self) -> Vec<(String, &str)> {
+        self.tables
+            .iter()
+            .filter(|table| table.strategy == CopyStrategy::TempTable)
+            .map(|table| (format!("temp_{}", table.name), table.name.as_str()))
+            .collect()
+    }
+
+    /// Temp table của các bảng `temp_table`. Tạo trên kết nối đích trước transaction
+    /// (tồn tại suốt session) để trong transaction chỉ còn INSERT / UPDATE / DELETE.
+    fn create_temp_tables(&self, conn: &mut PooledConn) -> Result<()> {
+        for (temp_table, table) in self.temp_tables() {
+            self.write_sql(
+                conn,
+                format!("DROP TEMPORARY TABLE IF EXISTS {}", temp_table),
+                (),
+            )?;
+            self.write_sql(
+                conn,
+                format!(
+                    "CREATE TEMPORARY TABLE {} AS SELECT * FROM `{}` WHERE 1 = 0",
+                    temp_table, table
+                ),
+                (),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn drop_temp_tables(&self, conn: &mut PooledConn) -> Result<()> {
+        let temp_tables: Vec<String> = self
+            .temp_tables()
+            .into_iter()
+            .map(|(temp_table, _)| temp_table)
+            .collect();
+        if temp_tables.is_empty() {
+            return Ok(());
+        }
+        self.write_sql(
+            conn,
+            format!("DROP TEMPORARY TABLE IF EXISTS {}", temp_tables.join(", ")),
+            (),
+        )
+    }
+
+    /// Kết nối đang có transaction InnoDB mở hay không. Đọc INNODB_TRX cần quyền PROCESS,
+    /// được gọi thử trước khi mở transaction merge để không lỗi quyền lúc commit.
+    fn transaction_active(conn: &mut PooledConn) -> Result<bool> {
+        let active: Option<i64> = conn
+            .query_first(
+                "SELECT COUNT(*) FROM INFORMATION_SCHEMA.INNODB_TRX
+                 WHERE trx_mysql_thread_id = CONNECTION_ID()",
+            )
+            .context(
+                "Không đọc được INFORMATION_SCHEMA.INNODB_TRX (user đích cần quyền PROCESS)",
+            )?;
+        Ok(active.unwrap_or(0) > 0)
+    }
+
+    /// Chặn commit nếu transaction đã bị đóng ngầm giữa chừng (vd: có câu DDL lọt vào),
+    /// vì khi đó ROLLBACK không còn tác dụng với phần dữ liệu đã ghi.
+    fn ensure_transaction_open(conn: &mut PooledConn) -> Result<()> {
+        if !Self::transaction_active(conn)? {
+            bail!("Transaction merge đã bị commit ngầm giữa chừng, dữ liệu KHÔNG còn rollback được. Kiểm tra lại database đích!");
+        }
+        Ok(())
+    }
+
+    /// Mapping ID của tất cả server nguồn, sắp xếp theo server / loại / ID cũ
+    fn collect_id_mappings(&self) -> Vec<IdMapping> {
+        let mut mappings = Vec::new();
+        for source in &self.sources {
+            for entity in self.tables.iter().filter_map(|table| table.entity.as_ref()) {
+                let Some(mapping) = source.mapping(entity) else {
+                    continue;
+                };
+                let mut ids: Vec<(&i32, &i32)> = mapping.iter().collect();
+                ids.sort();
+                mappings.extend(ids.into_iter().map(|(&old_id, &new_id)| IdMapping {
+                    server: source.config.server,
+                    entity: entity.clone(),
+                    old_id,
+                    new_id,
+                }));
+            }
+        }
+        mappings
+    }
+
+    /// Ghi mapping của 1 loại (account / player / clan) vào bảng `merge_id_map`
+    /// trong cùng transaction với dữ liệu merge
+    fn save_id_mappings(
+        &self,
+        conn: &mut PooledConn,
+        server: u8,
+        entity: &str,
+        mapping: &HashMap<i32, i32>,
+    ) -> Result<()> {
+        if !self.dry_run {
+            let mut ids: Vec<(&i32, &i32)> = mapping.iter().collect();
+            ids.sort();
+            for chunk in ids.chunks(STREAM_BATCH_ROWS) {
+                let mut params: Vec<Value> = Vec::with_capacity(chunk.len() * 5);
+                for (old_id, new_id) in chunk {
+                    params.push(Value::from(&self.run_id));
+                    params.push(Value::from(server));
+                    params.push(Value::from(entity));
+                    params.push(Value::from(**old_id));
+                    params.push(Value::from(**new_id));
+                }
+                self.write_sql(
+                    conn,
+                    format!(
+                        "INSERT INTO `{}` (`run_id`, `source_server`, `entity`, `old_id`, `new_id`) VALUES {}",
+                        ID_MAP_TABLE,
+                        vec!["(?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+                    ),
+                    Params::Positional(params),
+                )?;
+            }
+        }
+
+        println!(
+            "{} {} mapping {} lưu vào {}",
+            "✓".green(),
+            mapping.len(),
+            entity,
+            ID_MAP_TABLE
+        );
+        Ok(())
+    }
+
+    fn print_statistics(&mut self) -> Result<()> {
+        println!("\n{}", "=== THỐNG KÊ TRƯỚC KHI MERGE ===".bright_cyan());
+
+        let mut conns = vec![(self.target.server, self.target_pool.get_conn()?)];
+        for source in &self.sources {
+            conns.push((source.config.server, source.pool.get_conn()?));
+        }
+
+        for table in self.tables.iter().map(|t| t.name.as_str()) {
+            let mut line = format!("{:<25}", table);
+            let mut total = 0;
+            for (server, conn) in &mut conns {
+                // Bảng phụ có thể chưa có ở server cũ
+                let count = if Self::table_exists(conn, table)? {
+                    self.get_row_count(conn, table)?
+                } else {
+                    0
+                };
+                line.push_str(&format!(" | Server{}: {:>6}", server, count));
+                total += count;
+            }
+            println!("{} | Tổng: {:>6}", line, total);
+        }
+
+        println!("{}", "=".repeat(80));
+        Ok(())
+    }
+
+    /// Số row của các bảng bị merge trên đích, đọc trong transaction chưa commit
+    fn print_merge_result(&self, conn: &mut PooledConn) -> Result<()> {
+        println!(
+            "\n{}",
+            "=== KẾT QUẢ SAU MERGE (SẼ ROLLBACK) ===".bright_cyan()
+        );
+        for table in self.merged_tables() {
+            // Bảng phụ (vd: player_vip) có thể không tồn tại ở đích
+            let count = match self.get_row_count(conn, &table) {
+                Ok(count) => count.to_string(),
+                Err(_) => "-".to_string(),
+            };
+            println!("{:<25} | Server{}: {:>6}", table, self.target.server, count);
         }
-
+        println!("{}", "=".repeat(80));
         Ok(())
     }
 
-    fn print_statistics(&mut self) -> Result<()> {
-        println!("\n{}", "=== THỐNG KÊ TRƯỚC KHI MERGE ===".bright_cyan());
-
-        let mut server1_conn = self.server1_pool.get_conn()?;
-        let mut server2_conn = self.server2_pool.get_conn()?;
-
-        let clan_table = format!("clan_sv{}", self.config.merge.target_server);
-        let tables = vec!["account", "player", &clan_table, "gift_code_histories"];
+    /// Timing của batch INSERT. Worker đẩy batch qua hàng đợi để thread ghi chỉ đọc
+    /// timer sau khi đã chạy hết các câu lệnh của batch.
+    fn record_batch_timing(&self, server: u8, batch: InsertBatch) -> Result<()> {
+        if Self::in_worker() {
+            return Self::enqueue_write(QueuedWrite::Timing(server, batch));
+        }
+        self.record_timing(batch.timing(server));
+        Ok(())
+    }
 
-        for table in tables {
-            let count1 = self.get_row_count(&mut server1_conn, table)?;
-            let count2 = self.get_row_count(&mut server2_conn, table)?;
+    fn record_timing(&self, timing: InsertTiming) {
+        if self.dry_run {
+            return;
+        }
+        if timing.method == "load_data" {
             println!(
-                "{:<25} | Server1: {:>6} | Server2: {:>6} | Tổng: {:>6}",
-                table,
-                count1,
-                count2,
-                count1 + count2
+                "  {} rows / 1 câu LOAD DATA trong {} ms",
+                timing.rows,
+                timing.elapsed.as_millis()
+            );
+        } else {
+            println!(
+                "  {} rows / {} câu INSERT (batch {}) trong {} ms",
+                timing.rows,
+                timing.statements,
+                timing.batch_size,
+                timing.elapsed.as_millis()
             );
         }
-
-        println!("{}", "=".repeat(80));
-        Ok(())
+        if let (Some(per_row), Some(batched)) = (timing.per_row_ms(), timing.batched_ms()) {
+            println!(
+                "  Từng row ({} row mẫu): {:.3} ms/row, batch: {:.3} ms/row",
+                timing.sample_rows, per_row, batched
+            );
+        }
+        self.insert_timings.lock().unwrap().push(timing);
     }
 
     fn get_row_count(&self, conn: &mut PooledConn, table: &str) -> Result<i64> {
@@ -273,476 +2735,613 @@ impl MergeTool {
         Ok(count.unwrap_or(0))
     }
 
-    // Helper function để đọc BIT(1) từ MySQL
-    fn get_bit_as_bool(row: &Row, col: &str) -> Option<bool> {
-        // BIT(1) có thể trả về dạng bytes hoặc i8
-        if let Some(val) = row.get_opt::<Value, _>(col) {
-            match val {
-                Ok(Value::Bytes(bytes)) => {
-                    if bytes.is_empty() {
-                        Some(false)
-                    } else {
-                        Some(bytes[0] != 0)
-                    }
-                }
-                Ok(Value::Int(i)) => Some(i != 0),
-                Ok(Value::UInt(u)) => Some(u != 0),
-                Ok(Value::NULL) => None,
-                _ => None,
+    // Giá trị cột BIT đọc qua text protocol là bytes thô (vd: b"\x01"), đổi sang số
+    // để ghi lại đúng giá trị bằng INSERT / LOAD DATA
+    fn bit_value(value: Value) -> Value {
+        match value {
+            Value::Bytes(bytes) => {
+                Value::UInt(bytes.iter().fold(0u64, |acc, &b| (acc << 8) | u64::from(b)))
             }
-        } else {
-            None
+            other => other,
         }
     }
 
-    fn merge_accounts(
-        &mut self,
-        target_conn: &mut PooledConn,
-        source_conn: &mut PooledConn,
-    ) -> Result<()> {
-        println!("\n{}", ">>> Merge bảng ACCOUNT...".bright_yellow());
-
-        let accounts: Vec<Row> = source_conn.query("SELECT * FROM account")?;
-
-        let pb = ProgressBar::new(accounts.len() as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
-                .unwrap(),
-        );
+    /// ID trong 1 ô dữ liệu (text protocol trả số dạng bytes)
+    fn value_as_id(value: &Value) -> Option<i32> {
+        from_value_opt::<i64>(value.clone())
+            .ok()
+            .and_then(|id| i32::try_from(id).ok())
+    }
 
-        let total_accounts = accounts.len();
-
-        for row in accounts {
-            let old_id: i32 = row.get("id").unwrap();
-            let new_id = old_id + self.config.merge.id_offset;
-
-            // Lưu mapping
-            self.account_mapping.insert(old_id, new_id);
-
-            if !self.dry_run {
-                // Xử lý các cột BIT(1) đặc biệt
-                let is_daily = Self::get_bit_as_bool(&row, "is_daily");
-                let is_admin_bit = Self::get_bit_as_bool(&row, "isAdmin");
-
-                let params: Vec<Value> = vec![
-                    Value::from(new_id),
-                    Value::from(old_id),
-                    Value::from(row.get::<String, _>("username").unwrap()),
-                    Value::from(row.get::<String, _>("password").unwrap()),
-                    row.get::<Value, _>("create_time").unwrap_or(Value::NULL),
-                    row.get::<Value, _>("update_time").unwrap_or(Value::NULL),
-                    Value::from(row.get::<i16, _>("ban").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("point_post").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("last_post").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("role").unwrap_or(-1)),
-                    Value::from(row.get::<i8, _>("is_admin").unwrap_or(0)),
-                    row.get::<Value, _>("last_time_login")
-                        .unwrap_or(Value::NULL),
-                    row.get::<Value, _>("last_time_logout")
-                        .unwrap_or(Value::NULL),
-                    row.get::<Value, _>("ip_address").unwrap_or(Value::NULL),
-                    Value::from(row.get::<i32, _>("active").unwrap_or(0)),
-                    row.get::<Value, _>("reward").unwrap_or(Value::NULL),
-                    Value::from(row.get::<i32, _>("thoi_vang").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("server_login").unwrap_or(1)),
-                    Value::from(row.get::<i32, _>("new_reg").unwrap_or(0)),
-                    row.get::<Value, _>("ip").unwrap_or(Value::NULL),
-                    row.get::<Value, _>("phone").unwrap_or(Value::NULL),
-                    row.get::<Value, _>("last_server_change_time")
-                        .unwrap_or(Value::NULL),
-                    Value::from(row.get::<i32, _>("ruby").unwrap_or(0)),
-                    row.get::<Value, _>("count_card").unwrap_or(Value::NULL),
-                    row.get::<Value, _>("type_bonus").unwrap_or(Value::NULL),
-                    row.get::<Value, _>("ref").unwrap_or(Value::NULL),
-                    Value::from(row.get::<i32, _>("diemgioithieu").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("vnd_old").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("tongnap_old").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("gioithieu").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("tongnap").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("account_old").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("pointNap").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("vnd").unwrap_or(0)),
-                    Value::from(row.get::<i32, _>("tongnapcu").unwrap_or(0)),
-                    match is_daily {
-                        Some(b) => Value::from(b),
-                        None => Value::NULL,
-                    },
-                    row.get::<Value, _>("money").unwrap_or(Value::NULL),
-                    match is_admin_bit {
-                        Some(b) => Value::from(b),
-                        None => Value::NULL,
-                    },
-                    row.get::<Value, _>("purchasedGifts").unwrap_or(Value::NULL),
-                    row.get::<Value, _>("claimed_accumulate")
-                        .unwrap_or(Value::NULL),
-                    row.get::<Value, _>("ip_address_register")
-                        .unwrap_or(Value::NULL),
-                ];
-
-                target_conn.exec_drop(
-                    r"INSERT INTO account
-                (`id`, `old_id`, `username`, `password`, `create_time`, `update_time`, `ban`, `point_post`, `last_post`,
-                 `role`, `is_admin`, `last_time_login`, `last_time_logout`, `ip_address`, `active`, `reward`,
-                 `thoi_vang`, `server_login`, `new_reg`, `ip`, `phone`, `last_server_change_time`, `ruby`,
-                 `count_card`, `type_bonus`, `ref`, `diemgioithieu`, `vnd_old`, `tongnap_old`, `gioithieu`,
-                 `tongnap`, `account_old`, `pointNap`, `vnd`, `tongnapcu`, `is_daily`, `money`, `isAdmin`,
-                 `purchasedGifts`, `claimed_accumulate`, `ip_address_register`)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?,
-                        ?, ?, ?, ?, ?, ?, ?,
-                        ?, ?, ?, ?, ?, ?, ?,
-                        ?, ?, ?, ?, ?, ?, ?,
-                        ?, ?, ?, ?, ?, ?, ?, ?,
-                        ?, ?, ?)",
-                    Params::Positional(params),
-                )?;
-            }
+    fn table_exists(conn: &mut PooledConn, table: &str) -> Result<bool> {
+        let found: Option<String> = conn.exec_first(
+            "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+            (table,),
+        )?;
+        Ok(found.is_some())
+    }
 
-            pb.inc(1);
+    /// Tên mới đã lên kế hoạch cho bảng entity: (cột tên, ID cũ -> tên mới)
+    fn entity_renames<'a>(
+        source: &'a SourceServer,
+        table: &TableConfig,
+    ) -> Option<(&'static str, &'a HashMap<i32, String>)> {
+        match table.entity.as_deref()? {
+            "account" => Some(("username", &source.username_renames)),
+            "player" => Some(("name", &source.player_renames)),
+            _ if table.is_clan() => source
+                .clan_renames
+                .get(&table.name)
+                .map(|renames| ("name", renames)),
+            _ => None,
         }
-
-        pb.finish_with_message("✓ Hoàn thành");
-        println!("{} {} accounts", "✓".green(), total_accounts);
-        Ok(())
     }
 
-    fn merge_players(
-        &mut self,
+    /// Merge 1 bảng theo registry. Bảng entity trả về mapping ID cũ -> mới
+    /// (ID mới = ID cũ + offset của nguồn).
+    fn merge_table(
+        &self,
         target_conn: &mut PooledConn,
         source_conn: &mut PooledConn,
-    ) -> Result<()> {
-        println!("\n{}", ">>> Merge bảng PLAYER...".bright_yellow());
+        source: &SourceServer,
+        table: &TableConfig,
+    ) -> Result<Option<HashMap<i32, i32>>> {
+        println!(
+            "\n{}",
+            format!(">>> Merge bảng {}...", table.name.to_uppercase()).bright_yellow()
+        );
 
-        let clan_col = format!("clan_id_sv{}", self.config.merge.target_server);
-        let offset = self.config.merge.id_offset;
+        // Bảng phụ có thể chưa có ở server cũ
+        if table.entity.is_none() && !Self::table_exists(source_conn, &table.name)? {
+            println!(
+                "{} Server nguồn không có bảng {}, bỏ qua",
+                "-".dimmed(),
+                table.name
+            );
+            return Ok(None);
+        }
 
         // Build mapping trước
-        let players: Vec<Row> = source_conn.query("SELECT id FROM player")?;
-        let total_players = players.len();
-
-        let pb = ProgressBar::new(total_players as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
-                .unwrap(),
-        );
-        pb.set_message("Building mapping...");
+        let mapping = match &table.entity {
+            Some(_) => {
+                let ids: Vec<i32> = source_conn.query(format!(
+                    "SELECT `{}` FROM `{}`",
+                    table.primary_key, table.name
+                ))?;
+                let mut mapping = HashMap::with_capacity(ids.len());
+                for old_id in ids {
+                    let new_id = old_id.checked_add(source.id_offset).with_context(|| {
+                        format!("{} {} tràn INT khi cộng offset", table.name, old_id)
+                    })?;
+                    mapping.insert(old_id, new_id);
+                }
+                Some(mapping)
+            }
+            None => None,
+        };
 
-        for row in &players {
-            let old_id: i32 = row.get("id").unwrap();
-            let new_id = old_id + offset;
-            self.player_mapping.insert(old_id, new_id);
-            pb.inc(1);
+        if self.dry_run {
+            let count = self.get_row_count(source_conn, &format!("`{}`", table.name))?;
+            println!("{} {} rows {}", "✓".green(), count, table.name);
+            return Ok(mapping);
         }
 
-        if !self.dry_run {
-            pb.set_message("Đang tạo temp table...");
-            // Lấy danh sách cột của bảng player (trừ old_id)
-            let columns: Vec<String> = target_conn.query(
-                "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
-                 WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'player'
-                 AND COLUMN_NAME != 'old_id'
-                 ORDER BY ORDINAL_POSITION",
-            )?;
-
-            // Tạo danh sách cột với backticks cho các cột đặc biệt
-            let columns_escaped: Vec<String> = columns.iter().map(|c| format!("`{}`", c)).collect();
-            let columns_str = columns_escaped.join(", ");
+        let columns = self.copy_columns(target_conn, source_conn, table)?;
+        match table.strategy {
+            CopyStrategy::TempTable => {
+                self.copy_via_temp_table(target_conn, source_conn, source, table, &columns)?
+            }
+            CopyStrategy::Rows | CopyStrategy::LoadData => self.copy_rows(
+                target_conn,
+                source_conn,
+                source,
+                table,
+                &columns,
+                mapping.as_ref(),
+            )?,
+        }
 
-            // Tạo temp table với cấu trúc giống hệt (không có old_id)
-            let sql = format!(
-                "CREATE TEMPORARY TABLE temp_player AS SELECT {} FROM {}.player",
-                columns_str, self.config.server2.database
+        let unmapped = self
+            .unmapped_json_ids
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|u| u.server == source.config.server && u.table == table.name)
+            .count();
+        if unmapped > 0 {
+            println!(
+                "{} {} giá trị trong cột JSON không remap được, giữ nguyên (xem unmapped_json_ids.csv)",
+                "⚠".yellow(),
+                unmapped
             );
-            target_conn.query_drop(&sql)?;
-
-            pb.set_message("Đang update IDs...");
-            // Update IDs trong temp table
-            target_conn.query_drop(&format!("UPDATE temp_player SET `id` = `id` + {}", offset))?;
-            target_conn.query_drop(&format!(
-                "UPDATE temp_player SET `account_id` = `account_id` + {} WHERE `account_id` IS NOT NULL",
-                offset
-            ))?;
-            target_conn.query_drop(&format!(
-                "UPDATE temp_player SET `{}` = `{}` + {} WHERE `{}` != -1",
-                clan_col, clan_col, offset, clan_col
-            ))?;
-
-            // Thêm cột old_id vào temp table và tính giá trị
-            target_conn.query_drop("ALTER TABLE temp_player ADD COLUMN `old_id` INT NULL")?;
-            target_conn.query_drop(&format!(
-                "UPDATE temp_player SET `old_id` = `id` - {}",
-                offset
-            ))?;
-
-            pb.set_message("Đang insert vào player...");
-            // Insert với chỉ định rõ các cột
-            let insert_columns = format!("{}, `old_id`", columns_str);
-            let select_columns = format!("{}, `old_id`", columns_str);
-
-            target_conn.query_drop(&format!(
-                "INSERT INTO player ({}) SELECT {} FROM temp_player",
-                insert_columns, select_columns
-            ))?;
+        }
 
-            target_conn.query_drop("DROP TEMPORARY TABLE temp_player")?;
+        // Đánh dấu bắt đổi tên khi login cho các nhân vật bị đổi tên
+        if table.entity.as_deref() == Some("player")
+            && self.config.merge.player_name_conflict == NameConflictRule::ForceRename
+        {
+            self.write_batch(
+                target_conn,
+                format!(
+                    "UPDATE `{}` SET `{}` = 1 WHERE `{}` = ?",
+                    table.name, self.config.merge.force_rename_column, table.primary_key
+                ),
+                source
+                    .player_renames
+                    .keys()
+                    .map(|old_id| (old_id + source.id_offset,)),
+            )?;
         }
 
-        pb.finish_with_message("✓ Hoàn thành");
-        println!("{} {} players", "✓".green(), total_players);
-        Ok(())
+        Ok(mapping)
     }
 
-    fn merge_clans(
-        &mut self,
+    /// Cột chép từ nguồn: `columns` trong registry, mặc định là các cột có ở cả nguồn và
+    /// đích theo thứ tự của đích (bật `align_schema` thì thêm các cột chỉ có ở nguồn).
+    /// Cột tool tự thêm vào đích (vd: force_rename) không có ở nguồn nên tự bị loại.
+    /// Bảng không phải entity bỏ khóa chính để đích tự sinh, trừ khi khóa chính là
+    /// cột tham chiếu (vd: player_vip.player_id).
+    fn copy_columns(
+        &self,
         target_conn: &mut PooledConn,
         source_conn: &mut PooledConn,
-    ) -> Result<()> {
-        println!("\n{}", ">>> Merge bảng CLAN...".bright_yellow());
+        table: &TableConfig,
+    ) -> Result<Vec<String>> {
+        if let Some(columns) = &table.columns {
+            return Ok(columns.clone());
+        }
 
-        let table_name = format!("clan_sv{}", self.config.merge.target_server);
-        let offset = self.config.merge.id_offset;
+        let query = "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
+                     WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?
+                     ORDER BY ORDINAL_POSITION";
+        let source_columns: Vec<String> = source_conn.exec(query, (&table.name,))?;
+        let target_columns: Vec<String> = target_conn.exec(query, (&table.name,))?;
+
+        // Cột chỉ có ở nguồn được align_schema thêm vào cuối bảng đích
+        // (--emit-sql chưa chạy ALTER nên đích chưa có cột này)
+        let added = source_columns
+            .iter()
+            .filter(|c| self.config.merge.align_schema && !target_columns.contains(c))
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(target_columns
+            .into_iter()
+            .filter(|c| source_columns.contains(c))
+            .chain(added)
+            .filter(|c| table.old_id_column.as_ref() != Some(c))
+            .filter(|c| {
+                table.entity.is_some()
+                    || *c != table.primary_key
+                    || table.references.contains_key(c)
+            })
+            .collect())
+    }
 
-        // Build mapping trước
-        let query = format!("SELECT id FROM {}", table_name);
-        let clans: Vec<Row> = source_conn.query(&query)?;
-        let total_clans = clans.len();
+    /// Cột kiểu BIT của bảng đích
+    fn bit_columns(&self, conn: &mut PooledConn, table: &str) -> Result<Vec<String>> {
+        Ok(conn.exec(
+            "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND DATA_TYPE = 'bit'",
+            (table,),
+        )?)
+    }
 
-        let pb = ProgressBar::new(total_clans as u64);
+    /// Strategy `rows` / `load_data`: stream row nguồn, đổi khóa chính của entity sang ID mới,
+    /// tra mapping cho cột tham chiếu (không có trong mapping thì giữ nguyên)
+    /// rồi INSERT nhiều row theo `batch_size` hoặc ghi TSV để LOAD DATA.
+    fn copy_rows(
+        &self,
+        target_conn: &mut PooledConn,
+        source_conn: &mut PooledConn,
+        source: &SourceServer,
+        table: &TableConfig,
+        columns: &[String],
+        mapping: Option<&HashMap<i32, i32>>,
+    ) -> Result<()> {
+        // Đọc stream từng row thay vì nạp cả bảng vào bộ nhớ, COUNT(*) chỉ để hiện tiến độ
+        let total: Option<u64> =
+            source_conn.query_first(format!("SELECT COUNT(*) FROM `{}`", table.name))?;
+        let pb = ProgressBar::new(total.unwrap_or(0));
         pb.set_style(
             ProgressStyle::default_bar()
                 .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
                 .unwrap(),
         );
-        pb.set_message("Building mapping...");
 
-        for row in &clans {
-            let old_id: i32 = row.get("id").unwrap();
-            let new_id = old_id + offset;
-            self.clan_mapping.insert(old_id, new_id);
-            pb.inc(1);
+        let position = |column: &str| columns.iter().position(|c| c == column);
+        let pk_index = mapping.and(position(&table.primary_key));
+        let references: Vec<(usize, Option<&HashMap<i32, i32>>)> = columns
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| {
+                table
+                    .references
+                    .get(c)
+                    .map(|entity| (i, source.mapping(entity)))
+            })
+            .collect();
+        let bit_columns = self.bit_columns(target_conn, &table.name)?;
+        let bit_indexes: Vec<usize> = bit_columns.iter().filter_map(|c| position(c)).collect();
+        let renames = Self::entity_renames(source, table)
+            .and_then(|(column, renames)| position(column).map(|i| (i, renames)));
+        let resets: Vec<(usize, i64)> = table
+            .reset_columns
+            .iter()
+            .filter_map(|(column, value)| position(column).map(|i| (i, *value)))
+            .collect();
+        let json_columns: Vec<(usize, Vec<JsonReference>)> = Self::json_columns(source, table)?
+            .into_iter()
+            .filter_map(|(column, paths)| position(&column).map(|i| (i, paths)))
+            .collect();
+        let pk_position = position(&table.primary_key);
+
+        let mut insert_columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+        if let Some(old_id_column) = &table.old_id_column {
+            insert_columns.push(old_id_column);
         }
-
-        if !self.dry_run {
-            pb.set_position(0);
-            pb.set_message("Đang tạo temp table...");
-
-            // Lấy danh sách cột của bảng clan
-            let columns: Vec<String> = target_conn.query(&format!(
-                "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
-                     WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = '{}'
-                     ORDER BY ORDINAL_POSITION",
-                table_name
-            ))?;
-
-            // Tạo danh sách cột với backticks
-            let columns_escaped: Vec<String> = columns.iter().map(|c| format!("`{}`", c)).collect();
-            let columns_str = columns_escaped.join(", ");
-
-            // Tạo temp table với cấu trúc giống hệt
-            let sql = format!(
-                "CREATE TEMPORARY TABLE temp_clan AS SELECT {} FROM {}.{}",
-                columns_str, self.config.server2.database, table_name
-            );
-            target_conn.query_drop(&sql)?;
-
-            pb.set_message("Đang update IDs...");
-            // Update IDs trong temp table
-            target_conn.query_drop(&format!("UPDATE temp_clan SET `id` = `id` + {}", offset))?;
-
-            // Update members JSON - cập nhật player_id trong JSON
-            pb.set_message("Đang update members JSON...");
-            let temp_clans: Vec<Row> =
-                target_conn.query("SELECT `id`, `members` FROM temp_clan")?;
-
-            for row in &temp_clans {
-                let clan_id: i32 = row.get("id").unwrap();
-                let members_json: String = row.get("members").unwrap_or_default();
-
-                if !members_json.is_empty() {
-                    let updated_members = self.update_clan_members_json(&members_json)?;
-                    target_conn.exec_drop(
-                        "UPDATE temp_clan SET `members` = ? WHERE `id` = ?",
-                        (&updated_members, clan_id),
-                    )?;
+        // File SQL (--emit-sql) không đo thời gian nên không cần mẫu insert từng row
+        let mut batch =
+            InsertBatch::new(&table.name, &insert_columns, self.config.merge.batch_size)
+                .with_per_row_sample(if self.script.is_none() {
+                    PER_ROW_SAMPLE
+                } else {
+                    0
+                });
+        let mut bulk =
+            self.bulk_writer(source.config.server, table, &insert_columns, &bit_columns)?;
+
+        let select = columns
+            .iter()
+            .map(|c| format!("`{}`", c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut total_rows = 0;
+        let rows = source_conn.query_iter(format!("SELECT {} FROM `{}`", select, table.name))?;
+        for row in rows {
+            let mut values = row?.unwrap();
+            total_rows += 1;
+            let row_id = pk_position.and_then(|i| from_value_opt::<i64>(values[i].clone()).ok());
+
+            // Khóa chính của entity: ID mới, ID cũ ghi vào cột old_id
+            let old_id = pk_index.and_then(|i| Self::value_as_id(&values[i]));
+            if let (Some(i), Some(old_id), Some(mapping)) = (pk_index, old_id, mapping) {
+                if let Some(&new_id) = mapping.get(&old_id) {
+                    values[i] = Value::from(new_id);
                 }
-                pb.inc(1);
             }
 
-            pb.set_message("Đang insert vào clan...");
-            // Insert vào bảng chính
-            target_conn.query_drop(&format!(
-                "INSERT INTO {} ({}) SELECT {} FROM temp_clan",
-                table_name, columns_str, columns_str
-            ))?;
-
-            target_conn.query_drop("DROP TEMPORARY TABLE temp_clan")?;
-        }
+            for &(i, ref_mapping) in &references {
+                let new_id = Self::value_as_id(&values[i])
+                    .and_then(|id| ref_mapping.and_then(|m| m.get(&id)).copied());
+                if let Some(new_id) = new_id {
+                    values[i] = Value::from(new_id);
+                }
+            }
 
-        pb.finish_with_message("✓ Hoàn thành");
-        println!("{} {} clans", "✓".green(), total_clans);
-        Ok(())
-    }
+            for &(i, value) in &resets {
+                values[i] = Value::from(value);
+            }
 
-    fn update_clan_members_json(&self, json_str: &str) -> Result<String> {
-        // Parse outer array
-        let members_raw: Vec<JsonValue> = serde_json::from_str(json_str)?;
-        let mut updated_members: Vec<String> = Vec::new();
+            for &i in &bit_indexes {
+                values[i] = Self::bit_value(std::mem::replace(&mut values[i], Value::NULL));
+            }
 
-        for member_value in &members_raw {
-            // Mỗi member có thể là string chứa JSON hoặc object trực tiếp
-            let member_str = match member_value {
-                JsonValue::String(s) => s.clone(),
-                other => other.to_string(),
-            };
+            if let (Some((i, renames)), Some(old_id)) = (renames, old_id) {
+                if let Some(new_name) = renames.get(&old_id) {
+                    values[i] = Value::from(new_name);
+                }
+            }
 
-            // Parse member JSON string thành object
-            let mut member_obj: serde_json::Map<String, JsonValue> =
-                serde_json::from_str(&member_str)?;
-
-            // Update player id từ old_id sang new_id
-            if let Some(id_value) = member_obj.get("id") {
-                if let Some(old_id) = id_value.as_i64() {
-                    let old_id_i32 = old_id as i32;
-                    if let Some(&new_id) = self.player_mapping.get(&old_id_i32) {
-                        member_obj.insert("id".to_string(), JsonValue::from(new_id));
-                        info!("Updated member id: {} -> {}", old_id_i32, new_id);
+            // ID nằm trong cột JSON (vd: members[*].id của clan)
+            for (i, paths) in &json_columns {
+                if let Ok(json) = from_value_opt::<String>(values[*i].clone()) {
+                    if let Some(updated) = self.remap_json(source, table, row_id, &json, paths)? {
+                        values[*i] = Value::from(updated);
                     }
                 }
             }
 
-            // Convert back to string
-            let updated_member_str = serde_json::to_string(&member_obj)?;
-
-            // Giữ nguyên format gốc
-            if member_value.is_string() {
-                // Format gốc là array of strings
-                updated_members.push(updated_member_str);
-            } else {
-                // Format gốc là array of objects - return early
-                return self.update_clan_members_json_as_objects(json_str);
+            if table.old_id_column.is_some() {
+                values.push(old_id.map_or(Value::NULL, Value::from));
             }
-        }
-
-        // Rebuild array of strings
-        let result: Vec<JsonValue> = updated_members.into_iter().map(JsonValue::String).collect();
 
-        Ok(serde_json::to_string(&result)?)
-    }
+            if let Some(bulk) = &mut bulk {
+                bulk.push(&values)?;
+            } else if let Some((sql, params, timer)) = batch.push(values) {
+                self.write_timed(target_conn, sql, params, Some(timer))?;
+            }
 
-    // Fallback cho trường hợp format là array of objects
-    fn update_clan_members_json_as_objects(&self, json_str: &str) -> Result<String> {
-        let mut members: Vec<JsonValue> = serde_json::from_str(json_str)?;
+            pb.inc(1);
+        }
 
-        for member in &mut members {
-            if let Some(obj) = member.as_object_mut() {
-                if let Some(id) = obj.get("id").and_then(|v| v.as_i64()) {
-                    let old_id = id as i32;
-                    if let Some(&new_id) = self.player_mapping.get(&old_id) {
-                        obj.insert("id".to_string(), JsonValue::from(new_id));
-                    }
-                }
+        if let Some(bulk) = bulk {
+            pb.set_message("Đang LOAD DATA...");
+            self.load_bulk_file(target_conn, bulk.finish()?)?;
+        } else {
+            if let Some((sql, params, timer)) = batch.flush() {
+                self.write_timed(target_conn, sql, params, Some(timer))?;
             }
+            self.record_batch_timing(source.config.server, batch)?;
         }
 
-        Ok(serde_json::to_string(&members)?)
+        pb.finish_with_message("✓ Hoàn thành");
+        println!("{} {} rows {}", "✓".green(), total_rows, table.name);
+        Ok(())
     }
 
-    fn merge_gift_code_histories(
-        &mut self,
+    /// Strategy `temp_table`: chép nguồn vào temp table, cộng offset cho khóa chính
+    /// và cột tham chiếu (giá trị > 0) bằng UPDATE rồi `INSERT ... SELECT` vào bảng đích.
+    fn copy_via_temp_table(
+        &self,
         target_conn: &mut PooledConn,
         source_conn: &mut PooledConn,
+        source: &SourceServer,
+        table: &TableConfig,
+        columns: &[String],
     ) -> Result<()> {
-        println!("\n{}", ">>> Merge GIFT_CODE_HISTORIES...".bright_yellow());
+        let temp_table = format!("temp_{}", table.name);
+        let offset = source.id_offset;
+        let pk = &table.primary_key;
+
+        let pb = ProgressBar::new_spinner();
+        pb.set_message("Đang chép dữ liệu vào temp table...");
+
+        // Chép dữ liệu nguồn vào temp table, ID gốc được giữ ở cột old_id
+        self.copy_into_temp_table(
+            target_conn,
+            source_conn,
+            source,
+            table,
+            &temp_table,
+            columns,
+        )?;
 
-        let histories: Vec<Row> = source_conn.query("SELECT * FROM gift_code_histories")?;
-        let total_histories = histories.len();
+        pb.set_message("Đang update IDs...");
+        if table.entity.is_some() {
+            self.write_sql(
+                target_conn,
+                format!("UPDATE {} SET `{}` = `{}` + {}", temp_table, pk, pk, offset),
+                (),
+            )?;
+        }
+        for column in table.references.keys().filter(|c| columns.contains(c)) {
+            self.write_sql(
+                target_conn,
+                format!(
+                    "UPDATE {} SET `{}` = `{}` + {} WHERE `{}` > 0",
+                    temp_table, column, column, offset, column
+                ),
+                (),
+            )?;
+        }
 
-        let pb = ProgressBar::new(total_histories as u64);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
-                .unwrap(),
-        );
+        for (column, value) in table
+            .reset_columns
+            .iter()
+            .filter(|(c, _)| columns.contains(c))
+        {
+            self.write_sql(
+                target_conn,
+                format!("UPDATE {} SET `{}` = ?", temp_table, column),
+                (value,),
+            )?;
+        }
 
-        for row in &histories {
-            let old_player_id: i32 = row.get("player_id").unwrap();
-            let new_player_id = self
-                .player_mapping
-                .get(&old_player_id)
-                .copied()
-                .unwrap_or(old_player_id);
-
-            if !self.dry_run {
-                target_conn.exec_drop(
-                    r"INSERT INTO gift_code_histories
-                    (player_id, gift_code_id, code, type_clone, created_at)
-                    VALUES (?, ?, ?, ?, ?)",
-                    (
-                        new_player_id,
-                        row.get::<i32, _>("gift_code_id").unwrap(),
-                        row.get::<String, _>("code").unwrap(),
-                        row.get::<i32, _>("type_clone").unwrap_or(-1),
-                        row.get::<Option<String>, _>("created_at"),
-                    ),
-                )?;
+        // Đổi tên bị trùng
+        let renames = Self::entity_renames(source, table);
+        if let Some((column, renames)) = renames.filter(|(_, renames)| !renames.is_empty()) {
+            pb.set_message("Đang đổi tên trùng...");
+            self.write_batch(
+                target_conn,
+                format!(
+                    "UPDATE {} SET `{}` = ? WHERE `{}` = ?",
+                    temp_table, column, pk
+                ),
+                renames
+                    .iter()
+                    .map(|(old_id, new_name)| (new_name, old_id + offset)),
+            )?;
+        }
+
+        // ID nằm trong cột JSON, đọc từ nguồn (giống hệt temp table) để không phụ thuộc
+        // dữ liệu đã ghi ở đích
+        let json_columns: Vec<(String, Vec<JsonReference>)> = Self::json_columns(source, table)?
+            .into_iter()
+            .filter(|(column, _)| columns.contains(column))
+            .collect();
+        for (column, paths) in &json_columns {
+            pb.set_message(format!("Đang update JSON {}...", column));
+            let source_rows: Vec<(i64, Option<String>)> = source_conn.query(format!(
+                "SELECT `{}`, `{}` FROM `{}`",
+                pk, column, table.name
+            ))?;
+
+            for (id, json) in source_rows {
+                let json = json.unwrap_or_default();
+                if let Some(updated) = self.remap_json(source, table, Some(id), &json, paths)? {
+                    self.write_sql(
+                        target_conn,
+                        format!(
+                            "UPDATE {} SET `{}` = ? WHERE `{}` = ?",
+                            temp_table, column, pk
+                        ),
+                        (&updated, id + i64::from(offset)),
+                    )?;
+                }
             }
+        }
 
-            pb.inc(1);
+        pb.set_message(format!("Đang insert vào {}...", table.name));
+        let mut insert_columns: Vec<String> = columns.iter().map(|c| format!("`{}`", c)).collect();
+        if let Some(old_id_column) = &table.old_id_column {
+            insert_columns.push(format!("`{}`", old_id_column));
         }
+        let insert_columns = insert_columns.join(", ");
+        self.write_sql(
+            target_conn,
+            format!(
+                "INSERT INTO `{}` ({}) SELECT {} FROM {}",
+                table.name, insert_columns, insert_columns, temp_table
+            ),
+            (),
+        )?;
 
-        pb.finish_with_message("✓ Hoàn thành");
-        println!("{} {} gift histories", "✓".green(), total_histories);
+        self.write_sql(target_conn, format!("DELETE FROM {}", temp_table), ())?;
+
+        pb.finish_and_clear();
+        let count = self.get_row_count(source_conn, &format!("`{}`", table.name))?;
+        println!("{} {} rows {}", "✓".green(), count, table.name);
         Ok(())
     }
 
-    fn merge_other_tables(
-        &mut self,
+    /// Chép `columns` của bảng nguồn vào temp table (đã tạo sẵn bởi `create_temp_tables`),
+    /// bảng có `old_id_column` thì ghi thêm ID gốc vào cột đó.
+    /// Cùng MySQL instance thì dùng `INSERT ... SELECT` qua schema nguồn,
+    /// khác host thì stream row từ nguồn sang.
+    fn copy_into_temp_table(
+        &self,
         target_conn: &mut PooledConn,
         source_conn: &mut PooledConn,
+        source: &SourceServer,
+        table: &TableConfig,
+        temp_table: &str,
+        columns: &[String],
     ) -> Result<()> {
-        println!("\n{}", ">>> Merge các bảng phụ...".bright_yellow());
-
-        // Merge player_vip
-        if let Ok(vips) = source_conn.query::<Row, _>("SELECT * FROM player_vip") {
-            let total_vips = vips.len();
-            let pb = ProgressBar::new(total_vips as u64);
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} player_vip")
-                    .unwrap(),
-            );
-
-            for row in vips {
-                let old_player_id: i32 = row.get("player_id").unwrap();
-                let new_player_id = self
-                    .player_mapping
-                    .get(&old_player_id)
-                    .copied()
-                    .unwrap_or(old_player_id);
+        let columns_str = columns
+            .iter()
+            .map(|c| format!("`{}`", c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let (insert_str, select_str) = match &table.old_id_column {
+            Some(old_id_column) => (
+                format!("{}, `{}`", columns_str, old_id_column),
+                format!("{}, `{}`", columns_str, table.primary_key),
+            ),
+            None => (columns_str.clone(), columns_str),
+        };
+
+        self.write_sql(target_conn, format!("DELETE FROM {}", temp_table), ())?;
+
+        if source.same_instance {
+            self.write_sql(
+                target_conn,
+                format!(
+                    "INSERT INTO {} ({}) SELECT {} FROM `{}`.`{}`",
+                    temp_table, insert_str, select_str, source.config.db.database, table.name
+                ),
+                (),
+            )?;
+            return Ok(());
+        }
 
-                if !self.dry_run {
-                    target_conn.exec_drop(
-                        "INSERT INTO player_vip (player_id, vip_1, vip_2) VALUES (?, ?, ?)",
-                        (
-                            new_player_id,
-                            row.get::<bool, _>("vip_1").unwrap_or(false),
-                            row.get::<bool, _>("vip_2").unwrap_or(false),
-                        ),
-                    )?;
-                }
+        // Số row mỗi câu INSERT bị giới hạn theo số placeholder của bảng nhiều cột
+        let mut insert_columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+        if let Some(old_id_column) = &table.old_id_column {
+            insert_columns.push(old_id_column);
+        }
+        let mut batch = InsertBatch::new(temp_table, &insert_columns, STREAM_BATCH_ROWS);
 
-                pb.inc(1);
+        let result =
+            source_conn.exec_iter(format!("SELECT {} FROM `{}`", select_str, table.name), ())?;
+        for row in result {
+            if let Some((sql, params, _)) = batch.push(row?.unwrap()) {
+                self.write_sql(target_conn, sql, params)?;
             }
-
-            pb.finish_with_message("✓ Hoàn thành");
-            println!("{} {} player_vip records", "✓".green(), total_vips);
         }
+        if let Some((sql, params, _)) = batch.flush() {
+            self.write_sql(target_conn, sql, params)?;
+        }
+        Ok(())
+    }
 
-        println!("{} Hoàn thành merge bảng phụ", "✓".green());
+    fn detect_same_instances(&mut self) -> Result<()> {
+        // File SQL phải tự chứa dữ liệu, không được tham chiếu tới schema nguồn
+        if self.emit_sql.is_some() {
+            return Ok(());
+        }
+        for i in 0..self.sources.len() {
+            self.sources[i].same_instance = self.detect_same_instance(&self.sources[i])?;
+        }
         Ok(())
     }
 
+    /// Hai database nằm trên cùng MySQL instance thì có thể query chéo schema.
+    /// Hostname của 2 VPS có thể trùng nhau nên còn kiểm tra kết nối tới nguồn
+    /// có hiện trong PROCESSLIST của đích hay không.
+    fn detect_same_instance(&self, source: &SourceServer) -> Result<bool> {
+        let mut target_conn = self.target_pool.get_conn()?;
+        let mut source_conn = source.pool.get_conn()?;
+
+        let query = "SELECT @@hostname, @@port";
+        let target: Option<(String, u16)> = target_conn.query_first(query)?;
+        let source_host: Option<(String, u16)> = source_conn.query_first(query)?;
+        if target.is_none() || target != source_host {
+            return Ok(false);
+        }
+
+        let source_connection_id: Option<u64> =
+            source_conn.query_first("SELECT CONNECTION_ID()")?;
+        let visible: Option<i64> = target_conn.exec_first(
+            "SELECT COUNT(*) FROM INFORMATION_SCHEMA.PROCESSLIST WHERE ID = ? AND DB = ?",
+            (source_connection_id, &source.config.db.database),
+        )?;
+        Ok(visible.unwrap_or(0) > 0)
+    }
+
+    /// `json_references` của bảng gom theo cột, kèm mapping của entity được tham chiếu
+    fn json_columns<'a>(
+        source: &'a SourceServer,
+        table: &TableConfig,
+    ) -> Result<Vec<(String, Vec<JsonReference<'a>>)>> {
+        let mut columns: Vec<(String, Vec<JsonReference>)> = Vec::new();
+        for (path, entity) in &table.json_references {
+            let path = JsonPath::parse(path)?;
+            let reference = (path.clone(), source.mapping(entity));
+            match columns
+                .iter_mut()
+                .find(|(column, _)| *column == path.column)
+            {
+                Some((_, paths)) => paths.push(reference),
+                None => columns.push((path.column, vec![reference])),
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Đổi ID trong 1 giá trị cột JSON, ID không có trong mapping được ghi vào report
+    fn remap_json(
+        &self,
+        source: &SourceServer,
+        table: &TableConfig,
+        row_id: Option<i64>,
+        json: &str,
+        paths: &[JsonReference],
+    ) -> Result<Option<String>> {
+        let mut unmapped = Vec::new();
+        let updated = json_path::remap_column(json, paths, &mut unmapped).with_context(|| {
+            format!(
+                "JSON không hợp lệ ở {} (id {})",
+                table.name,
+                row_id.map_or("-".to_string(), |id| id.to_string())
+            )
+        })?;
+        if !unmapped.is_empty() {
+            self.unmapped_json_ids
+                .lock()
+                .unwrap()
+                .extend(unmapped.into_iter().map(|u| UnmappedJsonId {
+                    server: source.config.server,
+                    table: table.name.clone(),
+                    row_id,
+                    path: u.path,
+                    value: u.value,
+                    reason: u.reason.as_str(),
+                }));
+        }
+        Ok(updated)
+    }
+
     fn verify_merge(&self, conn: &mut PooledConn) -> Result<()> {
         println!("\n{}", "=== VERIFY KẾT QUẢ ===".bright_cyan());
 
@@ -791,6 +3390,288 @@ impl MergeTool {
     }
 }
 
+// ============ Restore ============
+
+fn run_restore(config: &Config, backup_dir: &Path, server: u8) -> Result<()> {
+    println!("\n{}", "=== RESTORE TỪ BACKUP ===".bright_cyan().bold());
+
+    let db_config = &config.server_db(server)?;
+
+    let manifest = backup::read_manifest(backup_dir)?;
+    let server_backup = manifest
+        .servers
+        .iter()
+        .find(|s| s.server == server)
+        .with_context(|| format!("Backup không chứa dữ liệu của Server {}", server))?;
+
+    println!(
+        "Backup: {} (tạo lúc {})",
+        backup_dir.display(),
+        manifest.created_at
+    );
+    println!(
+        "Database đích: {}:{}/{}",
+        db_config.host, db_config.port, db_config.database
+    );
+    if server_backup.database != db_config.database {
+        println!(
+            "{} Backup được tạo từ database {}, khác với database đích {}",
+            "⚠".yellow(),
+            server_backup.database,
+            db_config.database
+        );
+    }
+
+    // 1. Kiểm tra backup trước khi drop bất cứ thứ gì
+    println!("\n{}", ">>> Kiểm tra manifest...".bright_yellow());
+    backup::verify_server_backup(backup_dir, server_backup)?;
+
+    // 2. Liệt kê những gì sẽ bị ghi đè
+    let pool = MergeTool::create_pool(db_config)?;
+    let mut conn = pool.get_conn()?;
+
+    println!("\n{}", "Các bảng sẽ bị GHI ĐÈ:".red().bold());
+    for table in &server_backup.tables {
+        let exists: Option<String> = conn.exec_first(
+            "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+            (&table.table,),
+        )?;
+        let current = if exists.is_some() {
+            let count: Option<i64> =
+                conn.query_first(format!("SELECT COUNT(*) FROM `{}`", table.table))?;
+            count.unwrap_or(0).to_string()
+        } else {
+            "(chưa có)".to_string()
+        };
+        println!(
+            "{:<25} | Hiện tại: {:>8} | Sau restore: {:>8}",
+            table.table, current, table.row_count
+        );
+    }
+
+    // 3. Xác nhận từ user
+    println!(
+        "\n{} Dữ liệu hiện tại của các bảng trên sẽ bị XÓA. Tiếp tục? (yes/no): ",
+        "⚠".yellow()
+    );
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    if input.trim().to_lowercase() != "yes" {
+        println!("Đã hủy restore.");
+        return Ok(());
+    }
+
+    // 4. Restore
+    println!("\n{}", ">>> Đang restore...".bright_yellow());
+    backup::restore_server(&mut conn, backup_dir, server_backup)?;
+
+    println!("\n{}", "=== RESTORE THÀNH CÔNG ===".green().bold());
+    Ok(())
+}
+
+/// In khác biệt schema của 1 server nguồn theo từng bảng
+fn print_schema_differences(server: u8, differences: &[SchemaDifference]) {
+    if differences.is_empty() {
+        println!(
+            "{} Server {}: schema các bảng merge giống đích",
+            "✓".green(),
+            server
+        );
+        return;
+    }
+
+    println!("{}", format!("Server {}:", server).bold());
+    let mut tables: Vec<&str> = differences.iter().map(|d| d.table.as_str()).collect();
+    tables.dedup();
+    for table in tables {
+        println!("  {}", table);
+        for difference in differences.iter().filter(|d| d.table == table) {
+            let mark = match difference.severity {
+                Severity::Blocking => "✗".red(),
+                Severity::Safe => "-".dimmed(),
+            };
+            println!(
+                "    {} {:<25} {} (nguồn: {}, đích: {})",
+                mark, difference.item, difference.detail, difference.source, difference.target
+            );
+        }
+    }
+}
+
+fn run_schema_diff(config: &Config, server: Option<u8>, output: Option<&str>) -> Result<()> {
+    println!("\n{}", "=== SO SÁNH SCHEMA ===".bright_cyan().bold());
+
+    let (target, sources) = config.servers()?;
+    let mut target_conn = MergeTool::create_pool(&target.db)?.get_conn()?;
+    let clan_columns = MergeTool::detect_clan_columns(&mut target_conn)?;
+    let tables = config.tables(target.server, &clan_columns)?;
+    let tool_columns = config.tool_columns(&tables);
+    if let Some(server) = server {
+        if !sources.iter().any(|s| s.server == server) {
+            bail!("Không có server nguồn {} trong config", server);
+        }
+    }
+    println!(
+        "Database đích: {} | Bảng: {}",
+        target.db.describe(),
+        tables
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    // Nguồn khác vẫn được đọc để biết cột align_schema thêm vào có chặn nguồn đó không
+    let mut source_conns = Vec::new();
+    for source in &sources {
+        source_conns.push((
+            source.server,
+            MergeTool::create_pool(&source.db)?.get_conn()?,
+        ));
+    }
+    let results = schema::diff_sources(
+        &mut source_conns,
+        &mut target_conn,
+        &tables,
+        &tool_columns,
+        config.merge.align_schema,
+    )?;
+
+    let mut rows = Vec::new();
+    let mut blocking = 0;
+    for (source, differences) in results {
+        if server.is_some_and(|server| server != source) {
+            continue;
+        }
+        println!();
+        print_schema_differences(source, &differences);
+        for difference in differences {
+            if difference.severity == Severity::Blocking {
+                blocking += 1;
+            }
+            rows.push(vec![
+                source.to_string(),
+                difference.table,
+                difference.item,
+                difference.severity.as_str().to_string(),
+                difference.detail,
+                difference.source,
+                difference.target,
+            ]);
+        }
+    }
+
+    if let Some(path) = output {
+        report::write_csv(
+            Path::new(path),
+            &[
+                "server", "table", "item", "severity", "detail", "source", "target",
+            ],
+            rows.into_iter(),
+        )?;
+        println!("\nReport: {}", path);
+    }
+
+    println!();
+    if blocking > 0 {
+        bail!("Có {} khác biệt chặn merge", blocking);
+    }
+    println!("{} Không có khác biệt chặn merge", "✓".green().bold());
+    Ok(())
+}
+
+fn run_discover(config: &Config, write_config: Option<&str>) -> Result<()> {
+    println!("\n{}", "=== DISCOVER THAM CHIẾU ===".bright_cyan().bold());
+
+    let (target, _) = config.servers()?;
+    println!("Database đích: {}", target.db.describe());
+
+    let pool = MergeTool::create_pool(&target.db)?;
+    let mut conn = pool.get_conn()?;
+    let clan_columns = MergeTool::detect_clan_columns(&mut conn)?;
+    let tables = config.tables(target.server, &clan_columns)?;
+    let discovery = discover::discover(
+        &mut conn,
+        &tables,
+        &[ID_MAP_TABLE, CHECKPOINT_TABLE, RENAME_TABLE],
+    )?;
+
+    println!("\n{}", "Cột tham chiếu:".bright_yellow());
+    for reference in &discovery.references {
+        let status = match reference.status {
+            ReferenceStatus::Covered => "✓ đã remap".green(),
+            ReferenceStatus::MissingReference => "✗ thiếu trong references".red(),
+            ReferenceStatus::TableNotMerged => "✗ bảng chưa có trong registry".red(),
+            ReferenceStatus::NotAnEntity => "⚠ bảng được tham chiếu không phải entity".yellow(),
+            ReferenceStatus::Reset => "- đặt lại (reset_columns)".dimmed(),
+        };
+        println!(
+            "  {:<40} -> {:<20} [{}] {}",
+            format!("{}.{}", reference.table, reference.column),
+            reference.referenced_table,
+            if reference.foreign_key {
+                "FK"
+            } else {
+                "tên cột"
+            },
+            status
+        );
+    }
+    if discovery.references.is_empty() {
+        println!("  (không tìm thấy)");
+    }
+
+    if !discovery.unmerged_tables.is_empty() {
+        println!(
+            "\n{} Bảng không được merge và không có cột tham chiếu (dữ liệu nguồn sẽ bị bỏ qua):",
+            "⚠".yellow()
+        );
+        for table in &discovery.unmerged_tables {
+            println!("  - {}", table);
+        }
+    }
+    if !discovery.missing_tables.is_empty() {
+        println!(
+            "\n{} Bảng trong registry nhưng không có ở database đích: {}",
+            "⚠".yellow(),
+            discovery.missing_tables.join(", ")
+        );
+    }
+
+    let starter = discover::starter_tables(&discovery, &tables);
+    if starter.is_empty() {
+        println!(
+            "\n{} Registry đã remap mọi cột tham chiếu tìm thấy",
+            "✓".green()
+        );
+        return Ok(());
+    }
+    println!(
+        "\n{} {} bảng cần bổ sung vào [[tables]]: {}",
+        "⚠".yellow(),
+        starter.len(),
+        starter
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    match write_config {
+        Some(path) => {
+            fs::write(path, discover::starter_config(&starter)?)
+                .with_context(|| format!("Không thể ghi file {}", path))?;
+            println!(
+                "{} Đã ghi cấu hình mẫu ra {}, kiểm tra lại rồi chép vào config",
+                "✓".green(),
+                path
+            );
+        }
+        None => println!("Chạy lại với --write-config <FILE> để ghi cấu hình mẫu"),
+    }
+    Ok(())
+}
+
 // ============ Main Function ============
 
 fn main() -> Result<()> {
@@ -811,8 +3692,28 @@ fn main() -> Result<()> {
     let config_str = fs::read_to_string(config_path)?;
     let config: Config = toml::from_str(&config_str)?;
 
+    if let Some(Command::Restore { from, server }) = &args.command {
+        let server = match server {
+            Some(server) => *server,
+            None => config.servers()?.0.server,
+        };
+        return run_restore(&config, Path::new(from), server);
+    }
+    if let Some(Command::SchemaDiff { server, output }) = &args.command {
+        return run_schema_diff(&config, *server, output.as_deref());
+    }
+    if let Some(Command::Discover { write_config }) = &args.command {
+        return run_discover(&config, write_config.as_deref());
+    }
+
     // Tạo tool và chạy
-    let mut tool = MergeTool::new(config, args.dry_run)?;
+    let mut tool = MergeTool::new(
+        config,
+        args.dry_run,
+        args.skip_backup,
+        args.resume,
+        args.emit_sql,
+    )?;
     tool.execute()?;
 
     let duration = timer.elapsed();
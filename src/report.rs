@@ -0,0 +1,363 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// ============ Merge Report ============
+
+/// Account bị đổi username do trùng với server khác
+#[derive(Debug)]
+pub struct AccountRename {
+    pub server: u8,
+    pub account_id: i32,
+    pub old_username: String,
+    pub new_username: String,
+}
+
+/// Nhân vật bị đổi tên do trùng tên với server khác
+#[derive(Debug)]
+pub struct PlayerRename {
+    pub server: u8,
+    pub player_id: i32,
+    pub old_name: String,
+    pub new_name: String,
+    /// Game sẽ bắt nhân vật đổi tên ở lần login tới
+    pub force_rename: bool,
+}
+
+/// Clan bị đổi tên do trùng tên với server khác
+#[derive(Debug)]
+pub struct ClanRename {
+    pub server: u8,
+    pub table: String,
+    pub clan_id: i32,
+    pub old_name: String,
+    pub new_name: String,
+}
+
+/// Cột bị đặt lại giá trị cố định thay vì chép từ nguồn (`reset_columns`)
+#[derive(Debug)]
+pub struct ColumnReset {
+    pub server: u8,
+    pub table: String,
+    pub column: String,
+    pub value: i64,
+    /// Số row nguồn có giá trị khác `value` (bị mất tham chiếu)
+    pub rows: u64,
+}
+
+/// ID cũ -> ID mới của 1 account / player / clan từ server nguồn
+#[derive(Debug, Serialize)]
+pub struct IdMapping {
+    pub server: u8,
+    /// Entity trong registry bảng: `account`, `player`, `clan`...
+    pub entity: String,
+    pub old_id: i32,
+    pub new_id: i32,
+}
+
+/// Giá trị trong cột JSON (`json_references`) không remap được, được giữ nguyên:
+/// ID không có trong mapping hoặc chuỗi không phải JSON nằm trên path
+#[derive(Debug)]
+pub struct UnmappedJsonId {
+    pub server: u8,
+    pub table: String,
+    /// Khóa chính (ID cũ) của row chứa giá trị
+    pub row_id: Option<i64>,
+    pub path: String,
+    pub value: String,
+    /// `not_in_mapping` hoặc `invalid_json`
+    pub reason: &'static str,
+}
+
+/// Thời gian insert 1 bảng của 1 server nguồn theo batch_size đang dùng. Với INSERT,
+/// vài row đầu được insert từng row 1 làm mẫu để so sánh với phần insert nhiều row.
+#[derive(Debug)]
+pub struct InsertTiming {
+    pub server: u8,
+    pub table: String,
+    /// `insert` (INSERT nhiều row) hoặc `load_data` (LOAD DATA LOCAL INFILE)
+    pub method: &'static str,
+    pub rows: u64,
+    /// Số câu INSERT (round trip) đã gửi, kể cả các câu của mẫu
+    pub statements: u64,
+    /// Số row tối đa mỗi câu INSERT, 0 với LOAD DATA (nạp cả file 1 lần)
+    pub batch_size: usize,
+    /// Thời gian chạy câu lệnh trên đích (cả mẫu), không tính đọc nguồn
+    pub elapsed: Duration,
+    /// Số row mẫu insert từng row 1 (0: không lấy mẫu)
+    pub sample_rows: u64,
+    pub sample_elapsed: Duration,
+}
+
+impl InsertTiming {
+    /// ms / row khi insert từng row, đo trên mẫu
+    pub fn per_row_ms(&self) -> Option<f64> {
+        (self.sample_rows > 0)
+            .then(|| self.sample_elapsed.as_secs_f64() * 1000.0 / self.sample_rows as f64)
+    }
+
+    /// ms / row của phần còn lại (ngoài mẫu)
+    pub fn batched_ms(&self) -> Option<f64> {
+        let rows = self.rows - self.sample_rows;
+        (rows > 0)
+            .then(|| (self.elapsed - self.sample_elapsed).as_secs_f64() * 1000.0 / rows as f64)
+    }
+}
+
+/// Số liệu đã merge từ 1 server nguồn
+#[derive(Debug)]
+pub struct SourceSummary {
+    pub server: u8,
+    pub database: String,
+    pub id_offset: i32,
+    pub accounts: usize,
+    pub players: usize,
+    pub clans: usize,
+}
+
+/// Kết quả merge cần ghi ra file cho đội vận hành / support
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    pub account_renames: Vec<AccountRename>,
+    pub player_renames: Vec<PlayerRename>,
+    pub clan_renames: Vec<ClanRename>,
+    pub column_resets: Vec<ColumnReset>,
+    pub sources: Vec<SourceSummary>,
+    pub id_mappings: Vec<IdMapping>,
+    pub insert_timings: Vec<InsertTiming>,
+    pub unmapped_json_ids: Vec<UnmappedJsonId>,
+}
+
+impl MergeReport {
+    /// Ghi các file report vào `<report_directory>/<run_id>/`, trả về thư mục đã ghi
+    pub fn write(&self, base: &str, run_id: &str) -> Result<PathBuf> {
+        let dir = Path::new(base).join(run_id);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Không thể tạo thư mục report {}", dir.display()))?;
+
+        write_csv(
+            &dir.join("renamed_accounts.csv"),
+            &["server", "account_id", "old_username", "new_username"],
+            self.account_renames.iter().map(|r| {
+                vec![
+                    r.server.to_string(),
+                    r.account_id.to_string(),
+                    r.old_username.clone(),
+                    r.new_username.clone(),
+                ]
+            }),
+        )?;
+
+        write_csv(
+            &dir.join("renamed_players.csv"),
+            &[
+                "server",
+                "player_id",
+                "old_name",
+                "new_name",
+                "force_rename",
+            ],
+            self.player_renames.iter().map(|r| {
+                vec![
+                    r.server.to_string(),
+                    r.player_id.to_string(),
+                    r.old_name.clone(),
+                    r.new_name.clone(),
+                    r.force_rename.to_string(),
+                ]
+            }),
+        )?;
+
+        write_csv(
+            &dir.join("renamed_clans.csv"),
+            &["server", "table", "clan_id", "old_name", "new_name"],
+            self.clan_renames.iter().map(|r| {
+                vec![
+                    r.server.to_string(),
+                    r.table.clone(),
+                    r.clan_id.to_string(),
+                    r.old_name.clone(),
+                    r.new_name.clone(),
+                ]
+            }),
+        )?;
+
+        write_csv(
+            &dir.join("reset_columns.csv"),
+            &["server", "table", "column", "value", "rows"],
+            self.column_resets.iter().map(|r| {
+                vec![
+                    r.server.to_string(),
+                    r.table.clone(),
+                    r.column.clone(),
+                    r.value.to_string(),
+                    r.rows.to_string(),
+                ]
+            }),
+        )?;
+
+        write_csv(
+            &dir.join("sources.csv"),
+            &[
+                "server",
+                "database",
+                "id_offset",
+                "accounts",
+                "players",
+                "clans",
+            ],
+            self.sources.iter().map(|s| {
+                vec![
+                    s.server.to_string(),
+                    s.database.clone(),
+                    s.id_offset.to_string(),
+                    s.accounts.to_string(),
+                    s.players.to_string(),
+                    s.clans.to_string(),
+                ]
+            }),
+        )?;
+
+        write_csv(
+            &dir.join("id_mappings.csv"),
+            &["server", "entity", "old_id", "new_id"],
+            self.id_mappings.iter().map(|m| {
+                vec![
+                    m.server.to_string(),
+                    m.entity.clone(),
+                    m.old_id.to_string(),
+                    m.new_id.to_string(),
+                ]
+            }),
+        )?;
+
+        write_csv(
+            &dir.join("insert_timings.csv"),
+            &[
+                "server",
+                "table",
+                "method",
+                "rows",
+                "statements",
+                "batch_size",
+                "elapsed_ms",
+                "rows_per_sec",
+                "sample_rows",
+                "per_row_ms",
+                "batched_ms",
+                "speedup",
+            ],
+            self.insert_timings.iter().map(|t| {
+                let elapsed_ms = t.elapsed.as_millis();
+                let rows_per_sec = (u128::from(t.rows) * 1000)
+                    .checked_div(elapsed_ms)
+                    .map_or("-".to_string(), |v| v.to_string());
+                let ms = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.3}", v));
+                let speedup = t
+                    .per_row_ms()
+                    .zip(t.batched_ms())
+                    .filter(|(_, batched)| *batched > 0.0)
+                    .map(|(per_row, batched)| format!("{:.2}", per_row / batched));
+                vec![
+                    t.server.to_string(),
+                    t.table.clone(),
+                    t.method.to_string(),
+                    t.rows.to_string(),
+                    t.statements.to_string(),
+                    t.batch_size.to_string(),
+                    elapsed_ms.to_string(),
+                    rows_per_sec,
+                    t.sample_rows.to_string(),
+                    ms(t.per_row_ms()),
+                    ms(t.batched_ms()),
+                    speedup.unwrap_or_else(|| "-".to_string()),
+                ]
+            }),
+        )?;
+
+        write_csv(
+            &dir.join("unmapped_json_ids.csv"),
+            &["server", "table", "row_id", "path", "value", "reason"],
+            self.unmapped_json_ids.iter().map(|u| {
+                vec![
+                    u.server.to_string(),
+                    u.table.clone(),
+                    u.row_id.map_or(String::new(), |id| id.to_string()),
+                    u.path.clone(),
+                    u.value.clone(),
+                    u.reason.to_string(),
+                ]
+            }),
+        )?;
+
+        let json_path = dir.join("id_mappings.json");
+        let json = serde_json::json!({
+            "run_id": run_id,
+            "mappings": self.id_mappings,
+        });
+        fs::write(&json_path, serde_json::to_string_pretty(&json)?)
+            .with_context(|| format!("Không thể ghi {}", json_path.display()))?;
+
+        Ok(dir)
+    }
+}
+
+pub fn write_csv(
+    path: &Path,
+    header: &[&str],
+    rows: impl Iterator<Item = Vec<String>>,
+) -> Result<()> {
+    let mut content = header.join(",");
+    content.push('\n');
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        content.push_str(&fields.join(","));
+        content.push('\n');
+    }
+    fs::write(path, content).with_context(|| format!("Không thể ghi {}", path.display()))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// ============ Đổi tên khi trùng ============
+
+/// Tạo tên mới `base + suffix` chưa có trong `taken` (so sánh không phân biệt hoa thường
+/// như collation mặc định của MySQL), cắt bớt `base` nếu vượt quá `max_len` ký tự.
+/// Nếu vẫn trùng thì thêm số thứ tự: `name_s2`, `name_s2_2`, `name_s2_3`...
+pub fn unique_name(
+    base: &str,
+    suffix: &str,
+    max_len: Option<usize>,
+    taken: &HashSet<String>,
+) -> String {
+    let mut attempt = 1;
+    loop {
+        let full_suffix = if attempt == 1 {
+            suffix.to_string()
+        } else {
+            format!("{}_{}", suffix, attempt)
+        };
+        let base_len = max_len
+            .map(|max| max.saturating_sub(full_suffix.chars().count()))
+            .unwrap_or(usize::MAX);
+        let candidate: String = base.chars().take(base_len).collect::<String>() + &full_suffix;
+        if !taken.contains(&name_key(&candidate)) {
+            return candidate;
+        }
+        attempt += 1;
+    }
+}
+
+/// Khóa so sánh tên theo kiểu collation `_ci` của MySQL
+pub fn name_key(name: &str) -> String {
+    name.trim_end().to_lowercase()
+}
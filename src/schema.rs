@@ -0,0 +1,669 @@
+use anyhow::Result;
+use mysql::prelude::*;
+use mysql::*;
+use std::collections::BTreeMap;
+
+use crate::backup::sql_literal;
+use crate::config::TableConfig;
+
+// ============ Schema Diff ============
+
+/// Mức độ của 1 khác biệt schema giữa nguồn và đích
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Severity {
+    /// Merge vẫn chạy đúng, dữ liệu không bị mất
+    Safe,
+    /// Merge sẽ lỗi hoặc mất / cắt dữ liệu nguồn
+    Blocking,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Safe => "safe",
+            Severity::Blocking => "blocking",
+        }
+    }
+}
+
+/// 1 khác biệt của bảng trong registry giữa nguồn và đích
+#[derive(Debug)]
+pub struct SchemaDifference {
+    pub table: String,
+    /// Cột / index bị khác, rỗng nếu là khác biệt cấp bảng
+    pub item: String,
+    pub severity: Severity,
+    pub detail: String,
+    pub source: String,
+    pub target: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct ColumnSchema {
+    column_type: String,
+    nullable: bool,
+    default: Option<String>,
+    charset: Option<String>,
+    collation: Option<String>,
+    extra: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct IndexSchema {
+    unique: bool,
+    columns: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct TableSchema {
+    columns: BTreeMap<String, ColumnSchema>,
+    indexes: BTreeMap<String, IndexSchema>,
+}
+
+// TABLE_NAME, COLUMN_NAME, COLUMN_TYPE, IS_NULLABLE, COLUMN_DEFAULT, CHARACTER_SET_NAME,
+// COLLATION_NAME, EXTRA
+type ColumnRow = (
+    String,
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    String,
+);
+
+/// Đọc schema (cột + index) của các bảng trong database đang kết nối
+fn load_schema(conn: &mut PooledConn) -> Result<BTreeMap<String, TableSchema>> {
+    let mut tables: BTreeMap<String, TableSchema> = BTreeMap::new();
+
+    let columns: Vec<Row> = conn.query(
+        "SELECT TABLE_NAME, COLUMN_NAME, COLUMN_TYPE, IS_NULLABLE, COLUMN_DEFAULT,
+                CHARACTER_SET_NAME, COLLATION_NAME, EXTRA
+         FROM INFORMATION_SCHEMA.COLUMNS
+         WHERE TABLE_SCHEMA = DATABASE()",
+    )?;
+    for row in columns {
+        let (table, column, column_type, nullable, default, charset, collation, extra): ColumnRow =
+            from_row_opt(row)?;
+        tables.entry(table).or_default().columns.insert(
+            column,
+            ColumnSchema {
+                column_type,
+                nullable: nullable == "YES",
+                default,
+                charset,
+                collation,
+                extra: extra.to_lowercase(),
+            },
+        );
+    }
+
+    let indexes: Vec<(String, String, i64, Option<String>)> = conn.query(
+        "SELECT TABLE_NAME, INDEX_NAME, NON_UNIQUE, COLUMN_NAME
+         FROM INFORMATION_SCHEMA.STATISTICS
+         WHERE TABLE_SCHEMA = DATABASE()
+         ORDER BY TABLE_NAME, INDEX_NAME, SEQ_IN_INDEX",
+    )?;
+    for (table, index, non_unique, column) in indexes {
+        let index = tables
+            .entry(table)
+            .or_default()
+            .indexes
+            .entry(index)
+            .or_insert_with(|| IndexSchema {
+                unique: non_unique == 0,
+                columns: Vec::new(),
+            });
+        // Functional index (MySQL 8) không có tên cột
+        index
+            .columns
+            .push(column.unwrap_or_else(|| "(expr)".to_string()));
+    }
+
+    Ok(tables)
+}
+
+/// So sánh schema các bảng trong registry giữa từng nguồn và đích.
+/// `tool_columns` là cột tool tự thêm vào đích (old_id, force_rename...) theo bảng.
+/// Bật `align` thì cột chỉ có ở nguồn được tính là an toàn vì sẽ được thêm vào đích,
+/// trừ cột NOT NULL không có DEFAULT mà nguồn khác lại không có: row của nguồn đó
+/// không có giá trị cho cột nên INSERT sẽ lỗi.
+pub fn diff_sources(
+    sources: &mut [(u8, PooledConn)],
+    target_conn: &mut PooledConn,
+    tables: &[TableConfig],
+    tool_columns: &[(String, String)],
+    align: bool,
+) -> Result<Vec<(u8, Vec<SchemaDifference>)>> {
+    let target_schema = load_schema(target_conn)?;
+    let mut source_schemas = Vec::new();
+    for (server, conn) in sources.iter_mut() {
+        source_schemas.push((*server, load_schema(conn)?));
+    }
+
+    let mut results = Vec::new();
+    for (server, source_schema) in &source_schemas {
+        let mut differences = diff_schemas(source_schema, &target_schema, tables, tool_columns);
+        if align {
+            for addition in column_additions(source_schema, &target_schema, tables, tool_columns) {
+                let Some(difference) = differences
+                    .iter_mut()
+                    .find(|d| d.table == addition.table && d.item == addition.column)
+                else {
+                    continue;
+                };
+                // Nguồn có bảng nhưng không có cột (nguồn thiếu bảng thì không chép gì)
+                let lacking: Vec<String> = source_schemas
+                    .iter()
+                    .filter(|(other, schema)| {
+                        other != server
+                            && schema
+                                .get(&addition.table)
+                                .is_some_and(|t| !t.columns.contains_key(&addition.column))
+                    })
+                    .map(|(other, _)| other.to_string())
+                    .collect();
+                if addition.requires_value && !lacking.is_empty() {
+                    difference.detail = format!(
+                        "Cột NOT NULL không có DEFAULT được thêm vào đích (align_schema), server {} không có cột này nên INSERT sẽ lỗi",
+                        lacking.join(", ")
+                    );
+                } else {
+                    difference.severity = Severity::Safe;
+                    difference.detail =
+                        "Cột chỉ có ở nguồn, được thêm vào đích (align_schema)".to_string();
+                }
+            }
+        }
+        results.push((*server, differences));
+    }
+    Ok(results)
+}
+
+fn diff_schemas(
+    source_schema: &BTreeMap<String, TableSchema>,
+    target_schema: &BTreeMap<String, TableSchema>,
+    tables: &[TableConfig],
+    tool_columns: &[(String, String)],
+) -> Vec<SchemaDifference> {
+    let mut differences = Vec::new();
+    for table in tables {
+        let mut push = |item: &str, severity, detail: &str, source: String, target: String| {
+            differences.push(SchemaDifference {
+                table: table.name.clone(),
+                item: item.to_string(),
+                severity,
+                detail: detail.to_string(),
+                source,
+                target,
+            })
+        };
+
+        let (source, target) = match (
+            source_schema.get(&table.name),
+            target_schema.get(&table.name),
+        ) {
+            (Some(source), Some(target)) => (source, target),
+            (None, None) => continue,
+            (Some(_), None) => {
+                push(
+                    "",
+                    Severity::Blocking,
+                    "Bảng không có ở đích",
+                    "có".to_string(),
+                    "-".to_string(),
+                );
+                continue;
+            }
+            (None, Some(_)) => {
+                // Bảng phụ thiếu ở nguồn được bỏ qua khi merge, bảng entity thì không
+                let severity = if table.entity.is_some() {
+                    Severity::Blocking
+                } else {
+                    Severity::Safe
+                };
+                push(
+                    "",
+                    severity,
+                    "Bảng không có ở nguồn",
+                    "-".to_string(),
+                    "có".to_string(),
+                );
+                continue;
+            }
+        };
+
+        let copied = |column: &str| {
+            table
+                .columns
+                .as_ref()
+                .is_none_or(|columns| columns.iter().any(|c| c == column))
+        };
+
+        let tool_column = |column: &str| {
+            tool_columns
+                .iter()
+                .any(|(t, c)| *t == table.name && c == column)
+        };
+
+        for (name, column) in &source.columns {
+            if tool_column(name) {
+                continue;
+            }
+            match target.columns.get(name) {
+                None if copied(name) => push(
+                    name,
+                    Severity::Blocking,
+                    "Cột chỉ có ở nguồn, dữ liệu cột này sẽ không được chép",
+                    column.column_type.clone(),
+                    "-".to_string(),
+                ),
+                None => {}
+                Some(target_column) => {
+                    for (severity, detail, source, target) in diff_column(column, target_column) {
+                        push(name, severity, detail, source, target);
+                    }
+                }
+            }
+        }
+
+        for (name, column) in &target.columns {
+            if source.columns.contains_key(name) || tool_column(name) {
+                continue;
+            }
+            let severity = if column.nullable
+                || column.default.is_some()
+                || column.extra.contains("auto_increment")
+            {
+                Severity::Safe
+            } else {
+                Severity::Blocking
+            };
+            push(
+                name,
+                severity,
+                if severity == Severity::Safe {
+                    "Cột chỉ có ở đích, row nguồn nhận giá trị mặc định"
+                } else {
+                    "Cột chỉ có ở đích, NOT NULL và không có DEFAULT"
+                },
+                "-".to_string(),
+                column.column_type.clone(),
+            );
+        }
+
+        for (name, index) in &target.indexes {
+            let in_source = source
+                .indexes
+                .values()
+                .any(|s| s.columns == index.columns && (s.unique || !index.unique));
+            if in_source {
+                continue;
+            }
+            let (severity, detail) = if index.unique {
+                (
+                    Severity::Blocking,
+                    "UNIQUE chỉ có ở đích, dữ liệu nguồn có thể vi phạm",
+                )
+            } else {
+                (Severity::Safe, "Index chỉ có ở đích")
+            };
+            push(
+                &format!("INDEX {}", name),
+                severity,
+                detail,
+                "-".to_string(),
+                describe_index(index),
+            );
+        }
+        for (name, index) in &source.indexes {
+            if !target.indexes.values().any(|t| t.columns == index.columns) {
+                push(
+                    &format!("INDEX {}", name),
+                    Severity::Safe,
+                    "Index chỉ có ở nguồn",
+                    describe_index(index),
+                    "-".to_string(),
+                );
+            }
+        }
+    }
+
+    differences
+}
+
+/// Cột có ở nguồn nhưng thiếu ở đích, được thêm vào đích khi bật `align_schema`
+#[derive(Debug)]
+pub struct ColumnAddition {
+    pub table: String,
+    pub column: String,
+    /// Câu `ALTER TABLE ... ADD COLUMN` dùng kiểu / NULL / DEFAULT / charset của nguồn
+    pub statement: String,
+    /// NOT NULL và không có DEFAULT: row chép vào đích phải có giá trị cho cột
+    pub requires_value: bool,
+}
+
+/// Các cột của bảng trong registry chỉ có ở nguồn (bỏ qua cột không được chép và cột tool tự thêm).
+/// Cột AUTO_INCREMENT / generated không tự thêm được nên vẫn là khác biệt chặn merge.
+pub fn missing_columns(
+    source_conn: &mut PooledConn,
+    target_conn: &mut PooledConn,
+    tables: &[TableConfig],
+    tool_columns: &[(String, String)],
+) -> Result<Vec<ColumnAddition>> {
+    Ok(column_additions(
+        &load_schema(source_conn)?,
+        &load_schema(target_conn)?,
+        tables,
+        tool_columns,
+    ))
+}
+
+fn column_additions(
+    source_schema: &BTreeMap<String, TableSchema>,
+    target_schema: &BTreeMap<String, TableSchema>,
+    tables: &[TableConfig],
+    tool_columns: &[(String, String)],
+) -> Vec<ColumnAddition> {
+    let mut additions = Vec::new();
+    for table in tables {
+        let (Some(source), Some(target)) = (
+            source_schema.get(&table.name),
+            target_schema.get(&table.name),
+        ) else {
+            continue;
+        };
+        for (name, column) in &source.columns {
+            let copied = table
+                .columns
+                .as_ref()
+                .is_none_or(|columns| columns.contains(name));
+            let tool_column = tool_columns
+                .iter()
+                .any(|(t, c)| *t == table.name && c == name);
+            let generated = column.extra.contains("auto_increment")
+                || column.extra.contains("virtual generated")
+                || column.extra.contains("stored generated");
+            if target.columns.contains_key(name) || !copied || tool_column || generated {
+                continue;
+            }
+            additions.push(ColumnAddition {
+                table: table.name.clone(),
+                column: name.clone(),
+                statement: format!(
+                    "ALTER TABLE `{}` ADD COLUMN `{}` {}",
+                    table.name,
+                    name,
+                    column_definition(column)
+                ),
+                requires_value: !column.nullable && column.default.is_none(),
+            });
+        }
+    }
+    additions
+}
+
+/// Định nghĩa cột theo INFORMATION_SCHEMA của nguồn
+fn column_definition(column: &ColumnSchema) -> String {
+    let mut definition = column.column_type.clone();
+    if let (Some(charset), Some(collation)) = (&column.charset, &column.collation) {
+        definition.push_str(&format!(" CHARACTER SET {} COLLATE {}", charset, collation));
+    }
+    definition.push_str(if column.nullable {
+        " NULL"
+    } else {
+        " NOT NULL"
+    });
+
+    if let Some(default) = &column.default {
+        let expression = column.extra.contains("default_generated");
+        let default = if expression && default.to_lowercase().starts_with("current_timestamp") {
+            default.clone()
+        } else if expression {
+            format!("({})", default)
+        } else if default.starts_with('\'') || default.starts_with("b'") || default == "NULL" {
+            // MariaDB trả DEFAULT đã có nháy, BIT trả dạng b'0'
+            default.clone()
+        } else {
+            sql_literal(&Value::from(default.as_str()))
+        };
+        definition.push_str(&format!(" DEFAULT {}", default));
+    }
+
+    if let Some(on_update) = column.extra.find("on update ") {
+        definition.push_str(&format!(" {}", column.extra[on_update..].to_uppercase()));
+    }
+    definition
+}
+
+fn describe_index(index: &IndexSchema) -> String {
+    format!(
+        "{}({})",
+        if index.unique { "UNIQUE " } else { "" },
+        index.columns.join(", ")
+    )
+}
+
+/// Khác biệt của 1 cột có ở cả nguồn và đích
+fn diff_column(
+    source: &ColumnSchema,
+    target: &ColumnSchema,
+) -> Vec<(Severity, &'static str, String, String)> {
+    let mut differences = Vec::new();
+
+    if source.column_type != target.column_type {
+        differences.push(if type_fits(&source.column_type, &target.column_type) {
+            (
+                Severity::Safe,
+                "Khác kiểu, kiểu đích chứa được giá trị nguồn",
+                source.column_type.clone(),
+                target.column_type.clone(),
+            )
+        } else {
+            (
+                Severity::Blocking,
+                "Khác kiểu, giá trị nguồn có thể bị cắt hoặc lỗi",
+                source.column_type.clone(),
+                target.column_type.clone(),
+            )
+        });
+    }
+
+    if source.nullable != target.nullable {
+        differences.push(if target.nullable {
+            (
+                Severity::Safe,
+                "Đích cho phép NULL",
+                "NOT NULL".to_string(),
+                "NULL".to_string(),
+            )
+        } else {
+            (
+                Severity::Blocking,
+                "Đích NOT NULL nhưng nguồn cho phép NULL",
+                "NULL".to_string(),
+                "NOT NULL".to_string(),
+            )
+        });
+    }
+
+    if source.default != target.default {
+        differences.push((
+            Severity::Safe,
+            "Khác DEFAULT",
+            source.default.clone().unwrap_or("-".to_string()),
+            target.default.clone().unwrap_or("-".to_string()),
+        ));
+    }
+
+    if source.charset != target.charset {
+        // utf8 (utf8mb3) -> utf8mb4 không mất ký tự, các trường hợp khác có thể mất
+        let widened = source
+            .charset
+            .as_deref()
+            .is_some_and(|c| c.starts_with("utf8"))
+            && target.charset.as_deref() == Some("utf8mb4");
+        differences.push((
+            if widened {
+                Severity::Safe
+            } else {
+                Severity::Blocking
+            },
+            "Khác charset",
+            source.charset.clone().unwrap_or("-".to_string()),
+            target.charset.clone().unwrap_or("-".to_string()),
+        ));
+    } else if source.collation != target.collation {
+        differences.push((
+            Severity::Safe,
+            "Khác collation (ảnh hưởng so sánh / UNIQUE theo tên)",
+            source.collation.clone().unwrap_or("-".to_string()),
+            target.collation.clone().unwrap_or("-".to_string()),
+        ));
+    }
+
+    differences
+}
+
+/// Kiểu cột đích chứa được mọi giá trị của kiểu nguồn (số nguyên / chuỗi / decimal / float)
+fn type_fits(source: &str, target: &str) -> bool {
+    let parse = |column_type: &str| {
+        let unsigned = column_type.contains("unsigned");
+        let base = column_type
+            .split([' ', '('])
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let args: Vec<u64> = column_type
+            .split_once('(')
+            .and_then(|(_, rest)| rest.split_once(')'))
+            .map(|(args, _)| {
+                args.split(',')
+                    .filter_map(|a| a.trim().parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        (base, unsigned, args)
+    };
+    let (source_base, source_unsigned, source_args) = parse(source);
+    let (target_base, target_unsigned, target_args) = parse(target);
+
+    const INTEGERS: [&str; 5] = ["tinyint", "smallint", "mediumint", "int", "bigint"];
+    let integer_rank = |base: &str| INTEGERS.iter().position(|t| *t == base);
+    if let (Some(source_rank), Some(target_rank)) =
+        (integer_rank(&source_base), integer_rank(&target_base))
+    {
+        // Độ rộng hiển thị int(11) không ảnh hưởng giá trị
+        return match (source_unsigned, target_unsigned) {
+            (false, false) | (true, true) => target_rank >= source_rank,
+            (true, false) => target_rank > source_rank,
+            (false, true) => false,
+        };
+    }
+
+    // Độ dài tối đa của kiểu chuỗi / nhị phân
+    let capacity = |base: &str, args: &[u64]| -> Option<(bool, u64)> {
+        match base {
+            "char" | "varchar" => Some((false, *args.first()?)),
+            "tinytext" => Some((false, 255)),
+            "text" => Some((false, 65_535)),
+            "mediumtext" => Some((false, 16_777_215)),
+            "longtext" => Some((false, 4_294_967_295)),
+            "binary" | "varbinary" => Some((true, *args.first()?)),
+            "tinyblob" => Some((true, 255)),
+            "blob" => Some((true, 65_535)),
+            "mediumblob" => Some((true, 16_777_215)),
+            "longblob" => Some((true, 4_294_967_295)),
+            _ => None,
+        }
+    };
+    if let (Some((source_binary, source_len)), Some((target_binary, target_len))) = (
+        capacity(&source_base, &source_args),
+        capacity(&target_base, &target_args),
+    ) {
+        return source_binary == target_binary && target_len >= source_len;
+    }
+
+    match (source_base.as_str(), target_base.as_str()) {
+        ("decimal", "decimal") => {
+            let precision = |args: &[u64]| {
+                let digits = args.first().copied().unwrap_or(10);
+                let scale = args.get(1).copied().unwrap_or(0);
+                (digits.saturating_sub(scale), scale)
+            };
+            let (source_int, source_scale) = precision(&source_args);
+            let (target_int, target_scale) = precision(&target_args);
+            source_unsigned >= target_unsigned
+                && target_int >= source_int
+                && target_scale >= source_scale
+        }
+        ("float", "double") => source_unsigned >= target_unsigned,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_widening() {
+        assert!(type_fits("int(11)", "bigint(20)"));
+        assert!(type_fits("tinyint(4)", "int"));
+        assert!(type_fits("int", "int(11)"));
+        assert!(type_fits("int(10) unsigned", "bigint(20) unsigned"));
+        assert!(type_fits("int(10) unsigned", "bigint(20)"));
+        assert!(type_fits("smallint(5) unsigned", "mediumint(8)"));
+    }
+
+    #[test]
+    fn integer_narrowing_rejected() {
+        assert!(!type_fits("bigint(20)", "int(11)"));
+        assert!(!type_fits("int(11)", "smallint(6)"));
+        // unsigned -> signed cùng cỡ mất nửa trên, signed -> unsigned mất số âm
+        assert!(!type_fits("int(10) unsigned", "int(11)"));
+        assert!(!type_fits("int(11)", "int(10) unsigned"));
+        assert!(!type_fits("tinyint(4)", "bigint(20) unsigned"));
+    }
+
+    #[test]
+    fn string_length() {
+        assert!(type_fits("varchar(32)", "varchar(64)"));
+        assert!(type_fits("varchar(64)", "varchar(64)"));
+        assert!(type_fits("char(10)", "varchar(10)"));
+        assert!(type_fits("varchar(255)", "text"));
+        assert!(type_fits("text", "mediumtext"));
+        assert!(type_fits("varbinary(16)", "blob"));
+
+        assert!(!type_fits("varchar(64)", "varchar(32)"));
+        assert!(!type_fits("mediumtext", "text"));
+        assert!(!type_fits("text", "varchar(255)"));
+        // Chuỗi và nhị phân không đổi qua lại
+        assert!(!type_fits("varchar(16)", "varbinary(16)"));
+        assert!(!type_fits("blob", "text"));
+    }
+
+    #[test]
+    fn decimal_precision_and_scale() {
+        assert!(type_fits("decimal(10,2)", "decimal(12,2)"));
+        assert!(type_fits("decimal(10,2)", "decimal(12,4)"));
+        assert!(type_fits("decimal(10,2) unsigned", "decimal(10,2)"));
+        // decimal không tham số = decimal(10,0)
+        assert!(type_fits("decimal", "decimal(10,0)"));
+
+        // Thêm scale mà không thêm precision làm hẹp phần nguyên
+        assert!(!type_fits("decimal(10,2)", "decimal(10,4)"));
+        assert!(!type_fits("decimal(12,2)", "decimal(10,2)"));
+        assert!(!type_fits("decimal(10,4)", "decimal(12,2)"));
+        assert!(!type_fits("decimal(10,2)", "decimal(10,2) unsigned"));
+    }
+
+    #[test]
+    fn other_types() {
+        assert!(type_fits("float", "double"));
+        assert!(!type_fits("double", "float"));
+        assert!(!type_fits("int(11)", "varchar(11)"));
+        assert!(!type_fits("datetime", "timestamp"));
+    }
+}
@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use mysql::Params;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::backup::sql_literal;
+
+// ============ SQL Script (--emit-sql) ============
+
+/// File SQL chứa các câu lệnh merge theo đúng thứ tự sẽ chạy, để DBA review và tự apply
+pub struct SqlScript {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    statements: usize,
+}
+
+impl SqlScript {
+    /// Tạo file và ghi header (mỗi dòng thành 1 comment)
+    pub fn create(path: &Path, header: &[String]) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Không thể tạo file SQL {}", path.display()))?;
+        let mut script = Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            statements: 0,
+        };
+        for line in header {
+            script.comment(line)?;
+        }
+        writeln!(script.writer)?;
+        Ok(script)
+    }
+
+    pub fn comment(&mut self, text: &str) -> Result<()> {
+        for line in text.lines() {
+            writeln!(self.writer, "-- {}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Ghi 1 câu lệnh, tham số `?` được thay bằng literal SQL
+    pub fn statement(&mut self, sql: &str, params: Params) -> Result<()> {
+        let sql = match params {
+            Params::Positional(values) => {
+                let mut values = values.iter();
+                let mut inlined = String::with_capacity(sql.len());
+                for c in sql.chars() {
+                    if c == '?' {
+                        if let Some(value) = values.next() {
+                            inlined.push_str(&sql_literal(value));
+                            continue;
+                        }
+                    }
+                    inlined.push(c);
+                }
+                inlined
+            }
+            _ => sql.to_string(),
+        };
+        writeln!(self.writer, "{};", sql.trim_end())?;
+        self.statements += 1;
+        Ok(())
+    }
+
+    /// Đóng file, trả về đường dẫn và số câu lệnh đã ghi
+    pub fn finish(mut self) -> Result<(PathBuf, usize)> {
+        self.writer.flush()?;
+        Ok((self.path, self.statements))
+    }
+}